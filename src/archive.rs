@@ -0,0 +1,170 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, Cursor, Read, Seek};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use zip::ZipArchive;
+use crate::vfs::{DirEntry, Metadata, Os, Vfs};
+
+/// Where the zip is read from, the file on disk or its bytes when the backend has no disk path
+trait Source: Read + Seek + Send {}
+
+impl<T: Read + Seek + Send> Source for T {}
+
+struct Entry {
+    index: usize,
+    size: u64,
+    crc32: u32,
+}
+
+/// Zip file holding the content of a mod, as older workshop mods ship
+pub struct Archive {
+    /// Where the zip is, files inside it are `<root>/<path inside the zip>`
+    root: PathBuf,
+    zip: Mutex<ZipArchive<Box<dyn Source>>>,
+
+    /// Files by their `/` separated path inside the zip
+    entries: BTreeMap<String, Entry>,
+}
+
+impl Archive {
+    /// Opens the zip at `path` in `vfs`, see [Mounted] to make it readable as a folder
+    pub fn open<V: Vfs>(vfs: &V, path: &Path) -> io::Result<Archive> {
+        let source: Box<dyn Source> = match vfs.disk_path(path) {
+            Some(file) => Box::new(File::open(file)?),
+            None => Box::new(Cursor::new(vfs.read(path)?)),
+        };
+        let mut zip = ZipArchive::new(source).map_err(to_io)?;
+        let mut entries = BTreeMap::new();
+        for index in 0..zip.len() {
+            let file = zip.by_index_raw(index).map_err(to_io)?;
+            if file.is_file() {
+                let name = file.name().replace('\\', "/").trim_start_matches("./").trim_start_matches('/').to_string();
+                entries.insert(name, Entry { index, size: file.size(), crc32: file.crc32() });
+            }
+        }
+        Ok(Archive { root: path.to_path_buf(), zip: Mutex::new(zip), entries })
+    }
+
+    /// `/` separated name of `path` inside the zip, empty for the zip itself
+    fn name_of(&self, path: &Path) -> io::Result<String> {
+        path.strip_prefix(&self.root)
+            .map(|x| x.to_string_lossy().replace('\\', "/"))
+            .map_err(|_| io::Error::new(io::ErrorKind::NotFound, "not in the archive"))
+    }
+
+    /// Entries below the folder `name`, with the rest of their name
+    fn below<'a>(&'a self, name: &str) -> impl Iterator<Item = (&'a str, &'a Entry)> + 'a {
+        let prefix = if name.is_empty() { String::new() } else { format!("{}/", name) };
+        self.entries
+            .range(prefix.clone()..)
+            .map_while(move |(k, v)| k.strip_prefix(prefix.as_str()).map(|x| (x, v)))
+    }
+}
+
+impl Vfs for Archive {
+    fn read_dir(&self, dir: &Path) -> io::Result<Vec<DirEntry>> {
+        let name = self.name_of(dir)?;
+        let mut entries = BTreeMap::new();
+        for (rest, _) in self.below(&name) {
+            let (child, is_dir) = match rest.split_once('/') {
+                Some((child, _)) => (child, true),
+                None => (rest, false),
+            };
+            *entries.entry(dir.join(child)).or_insert(false) |= is_dir;
+        }
+        if entries.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "no such folder in the archive"));
+        }
+        Ok(entries.into_iter().map(|(path, is_dir)| DirEntry { path, is_dir }).collect())
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        let name = self.name_of(path)?;
+        let entry = self.entries.get(&name).ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "not in the archive"))?;
+        let mut zip = self.zip.lock().unwrap();
+        let mut file = zip.by_index(entry.index).map_err(to_io)?;
+        let mut data = Vec::with_capacity(entry.size as usize);
+        file.read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    /// Size and CRC-32 come from the zip directory so nothing is unpacked
+    fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        let name = self.name_of(path)?;
+        match self.entries.get(&name) {
+            Some(entry) => Ok(Metadata { is_dir: false, len: entry.size, modified: None, checksum: Some(entry.crc32 as u64) }),
+            None if name.is_empty() || self.below(&name).next().is_some() => Ok(Metadata { is_dir: true, len: 0, modified: None, checksum: None }),
+            None => Err(io::Error::new(io::ErrorKind::NotFound, "not in the archive")),
+        }
+    }
+}
+
+fn to_io(e: zip::result::ZipError) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+/// Files of `V`, the disk by default, with every mounted archive readable as a folder,
+/// where the tool reads mods from
+pub struct Mounted<V = Os> {
+    vfs: V,
+
+    /// Zip files whose content the readers see as if the zip were a folder
+    archives: RwLock<BTreeMap<PathBuf, Arc<Archive>>>,
+}
+
+impl<V: Vfs> Mounted<V> {
+    pub fn new(vfs: V) -> Mounted<V> {
+        Mounted { vfs, archives: RwLock::default() }
+    }
+
+    /// Mounted archive holding `path`
+    fn find(&self, path: &Path) -> Option<Arc<Archive>> {
+        let archives = self.archives.read().unwrap();
+        if archives.is_empty() {
+            return None;
+        }
+        path.ancestors().find_map(|root| archives.get(root).cloned())
+    }
+}
+
+impl<V: Vfs> Vfs for Mounted<V> {
+    fn read_dir(&self, dir: &Path) -> io::Result<Vec<DirEntry>> {
+        match self.find(dir) {
+            Some(archive) => archive.read_dir(dir),
+            None => self.vfs.read_dir(dir),
+        }
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        match self.find(path) {
+            Some(archive) => archive.read(path),
+            None => self.vfs.read(path),
+        }
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        match self.find(path) {
+            Some(archive) => archive.metadata(path),
+            None => self.vfs.metadata(path),
+        }
+    }
+
+    fn disk_path(&self, path: &Path) -> Option<PathBuf> {
+        match self.find(path) {
+            Some(_) => None,
+            None => self.vfs.disk_path(path),
+        }
+    }
+
+    /// Makes every file in the zip at `path` readable as `<path>/<path inside the zip>`
+    fn mount(&self, path: &Path) -> io::Result<()> {
+        if self.archives.read().unwrap().contains_key(path) {
+            return Ok(());
+        }
+
+        let archive = Archive::open(&self.vfs, path)?;
+        self.archives.write().unwrap().insert(path.to_path_buf(), Arc::new(archive));
+        Ok(())
+    }
+}
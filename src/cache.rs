@@ -0,0 +1,357 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::OnceLock;
+use std::time::SystemTime;
+use log::{info, warn};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use xxhash_rust::xxh3::xxh3_64;
+use crate::vfs::Vfs;
+use crate::checks::FileIndex;
+use crate::data::{ResearchArea, TechnologyData};
+use crate::localisation::Languages;
+use crate::VERSION;
+
+/// Bumped whenever the layout or meaning of cached values changes without a version bump,
+/// entries written with another format are parsed again
+const FORMAT: u32 = 9;
+
+const DEFAULT_DIR: &str = "cache";
+
+static CACHE: OnceLock<ParseCache> = OnceLock::new();
+
+/// On-disk cache of per-file parse results, one entry per source file.
+///
+/// An entry is reused when size and modification time match, or when the content hash
+/// still matches after the file was touched. Files in zip archives are checked by the size and
+/// CRC-32 the zip stores for them instead. Entries written by another tool version are ignored.
+pub struct ParseCache {
+    dir: PathBuf,
+
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+    stale: AtomicUsize,
+    errors: AtomicUsize,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Entry<T> {
+    version: String,
+    format: u32,
+    path: PathBuf,
+    size: u64,
+    modified: Option<SystemTime>,
+    hash: u64,
+    value: T,
+}
+
+/// Conversion to a serde friendly form, [`TechnologyData`] only knows how to deserialize from jomini
+pub trait Cacheable: Sized {
+    type Repr: Serialize + DeserializeOwned;
+
+    fn to_repr(&self) -> Self::Repr;
+
+    fn from_repr(repr: Self::Repr) -> Self;
+}
+
+impl Cacheable for (BTreeMap<String, String>, FileIndex) {
+    type Repr = Self;
+
+    fn to_repr(&self) -> Self::Repr {
+        self.clone()
+    }
+
+    fn from_repr(repr: Self::Repr) -> Self {
+        repr
+    }
+}
+
+impl Cacheable for ((Languages, BTreeMap<String, String>), FileIndex) {
+    type Repr = (String, BTreeMap<String, String>, FileIndex);
+
+    fn to_repr(&self) -> Self::Repr {
+        (self.0.0.to_string(), self.0.1.clone(), self.1.clone())
+    }
+
+    fn from_repr(repr: Self::Repr) -> Self {
+        ((repr.0.parse().unwrap_or_default(), repr.1), repr.2)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CachedTechnologyData {
+    cost: Option<String>,
+    tier: Option<String>,
+    category: Vec<String>,
+    weight: Vec<String>,
+    area: String,
+    prerequisites: Vec<String>,
+    start_tech: bool,
+    name: Option<String>,
+    desc: Option<String>,
+}
+
+impl Cacheable for (BTreeMap<String, String>, HashMap<String, TechnologyData>, FileIndex) {
+    type Repr = (BTreeMap<String, String>, HashMap<String, CachedTechnologyData>, FileIndex);
+
+    fn to_repr(&self) -> Self::Repr {
+        (self.0.clone(), self.1.iter().map(|(k, x)| (k.clone(), CachedTechnologyData {
+            cost: x.cost.clone(),
+            tier: x.tier.clone(),
+            category: x.category.clone(),
+            weight: x.weight.clone(),
+            area: x.area.to_string(),
+            prerequisites: x.prerequisites.clone(),
+            start_tech: x.start_tech,
+            name: x.name.clone(),
+            desc: x.desc.clone(),
+        })).collect(), self.2.clone())
+    }
+
+    fn from_repr(repr: Self::Repr) -> Self {
+        (repr.0, repr.1.into_iter().map(|(k, x)| (k, TechnologyData {
+            cost: x.cost,
+            tier: x.tier,
+            category: x.category,
+            weight: x.weight,
+            area: x.area.parse::<ResearchArea>().unwrap_or_default(),
+            prerequisites: x.prerequisites,
+            start_tech: x.start_tech,
+            name: x.name,
+            desc: x.desc,
+        })).collect(), repr.2)
+    }
+}
+
+/// Enables the process wide cache, readers bypass it until this is called
+pub fn init(dir: Option<PathBuf>) {
+    let dir = dir.unwrap_or_else(|| PathBuf::from(DEFAULT_DIR));
+    if let Err(e) = fs::create_dir_all(&dir) {
+        warn!("Cannot create cache folder {}, {}", dir.display(), e);
+        return;
+    }
+
+    let _ = CACHE.set(ParseCache::new(dir));
+}
+
+/// Returns the cached parse result of `path` in `vfs` or runs `parse` and stores its result.
+/// Failing to read or write the cache never fails the parse.
+pub fn cached<S, V, E, F>(vfs: &S, path: &Path, parse: F) -> Result<V, E>
+where
+    S: Vfs,
+    V: Cacheable,
+    F: FnOnce() -> Result<V, E>,
+{
+    match CACHE.get() {
+        Some(cache) => cache.get_or_parse(vfs, path, parse),
+        None => parse(),
+    }
+}
+
+/// Logs hit and miss counts of this run
+pub fn report() {
+    if let Some(cache) = CACHE.get() {
+        info!(
+            "Parse cache: {} hits, {} misses ({} stale), {} errors",
+            cache.hits.load(Ordering::Relaxed),
+            cache.misses.load(Ordering::Relaxed),
+            cache.stale.load(Ordering::Relaxed),
+            cache.errors.load(Ordering::Relaxed),
+        );
+    }
+}
+
+impl ParseCache {
+    fn new(dir: PathBuf) -> ParseCache {
+        ParseCache {
+            dir,
+            hits: AtomicUsize::new(0),
+            misses: AtomicUsize::new(0),
+            stale: AtomicUsize::new(0),
+            errors: AtomicUsize::new(0),
+        }
+    }
+
+    fn entry_path(&self, path: &Path) -> PathBuf {
+        let key = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+        self.dir.join(format!("{:016x}.bin", xxh3_64(key.to_string_lossy().as_bytes())))
+    }
+
+    fn get_or_parse<S, V, E, F>(&self, vfs: &S, path: &Path, parse: F) -> Result<V, E>
+    where
+        S: Vfs,
+        V: Cacheable,
+        F: FnOnce() -> Result<V, E>,
+    {
+        // Zip entries carry a checksum, disk files are only hashed when touched
+        match vfs.metadata(path) {
+            Ok(metadata) => self.lookup(path, metadata.len, metadata.modified, || metadata.checksum.or_else(|| vfs.read(path).map(|x| xxh3_64(&x)).ok()), parse),
+            Err(_) => parse(),
+        }
+    }
+
+    /// `hash` is only computed when size and modification time do not settle it
+    fn lookup<V, E, F, H>(&self, path: &Path, size: u64, modified: Option<SystemTime>, hash: H, parse: F) -> Result<V, E>
+    where
+        V: Cacheable,
+        F: FnOnce() -> Result<V, E>,
+        H: Fn() -> Option<u64>,
+    {
+        let entry_path = self.entry_path(path);
+
+        let mut current = None;
+        if let Some(entry) = self.load::<V::Repr>(&entry_path) {
+            if entry.version == VERSION && entry.format == FORMAT && entry.path == path && entry.size == size {
+                if entry.modified.is_some() && entry.modified == modified {
+                    self.hits.fetch_add(1, Ordering::Relaxed);
+                    return Ok(V::from_repr(entry.value));
+                }
+
+                // Touched but maybe not changed, e.g. after a workshop update or a checkout
+                current = hash();
+                if current == Some(entry.hash) {
+                    self.hits.fetch_add(1, Ordering::Relaxed);
+                    let value = V::from_repr(entry.value);
+                    self.store(&entry_path, path, size, modified, entry.hash, &value);
+                    return Ok(value);
+                }
+            }
+            self.stale.fetch_add(1, Ordering::Relaxed);
+        }
+
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let value = parse()?;
+
+        if let Some(hash) = current.or_else(hash) {
+            self.store(&entry_path, path, size, modified, hash, &value);
+        }
+        Ok(value)
+    }
+
+    fn load<T: DeserializeOwned>(&self, entry_path: &Path) -> Option<Entry<T>> {
+        let bytes = fs::read(entry_path).ok()?;
+        match bincode::deserialize(&bytes) {
+            Ok(entry) => Some(entry),
+            Err(_) => {
+                // Written by an older format, will be replaced
+                self.errors.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    fn store<V: Cacheable>(&self, entry_path: &Path, path: &Path, size: u64, modified: Option<SystemTime>, hash: u64, value: &V) {
+        let entry = Entry {
+            version: VERSION.to_string(),
+            format: FORMAT,
+            path: path.to_path_buf(),
+            size,
+            modified,
+            hash,
+            value: value.to_repr(),
+        };
+
+        let result = bincode::serialize(&entry)
+            .map_err(io::Error::other)
+            .and_then(|bytes| {
+                // Write then rename so a concurrent or interrupted run never sees half an entry
+                let tmp = entry_path.with_extension(format!("{}.tmp", std::process::id()));
+                fs::write(&tmp, bytes)?;
+                fs::rename(&tmp, entry_path)
+            });
+
+        if let Err(e) = result {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+            warn!("Writing cache entry for {} failed, {}", path.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    type Variables = (BTreeMap<String, String>, FileIndex);
+
+    /// A cache in a fresh folder below the temp folder, removed again when dropped
+    struct TempCache(ParseCache);
+
+    impl TempCache {
+        fn new(name: &str) -> TempCache {
+            let dir = std::env::temp_dir().join(format!("stellaris-techtree-cache-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            TempCache(ParseCache::new(dir))
+        }
+
+        fn counts(&self) -> [usize; 4] {
+            [&self.0.hits, &self.0.misses, &self.0.stale, &self.0.errors].map(|x| x.load(Ordering::Relaxed))
+        }
+    }
+
+    impl Drop for TempCache {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0.dir);
+        }
+    }
+
+    fn variables(value: &str) -> Result<Variables, ()> {
+        Ok((BTreeMap::from([("@x".to_string(), value.to_string())]), FileIndex::default()))
+    }
+
+    fn unparsed() -> Result<Variables, ()> {
+        panic!("the cached value should be used")
+    }
+
+    #[test]
+    fn lookup_reuses_entries_until_the_file_changes() {
+        let cache = TempCache::new("lookup");
+        let path = Path::new("mod/common/scripted_variables/vars.txt");
+        let modified = Some(SystemTime::UNIX_EPOCH + Duration::from_secs(100));
+        let touched = Some(SystemTime::UNIX_EPOCH + Duration::from_secs(200));
+        let edited = Some(SystemTime::UNIX_EPOCH + Duration::from_secs(300));
+
+        let miss = cache.0.lookup(path, 10, modified, || Some(1), || variables("1")).unwrap();
+        assert_eq!(miss.0["@x"], "1");
+        assert_eq!(cache.counts(), [0, 1, 0, 0]);
+
+        let hit = cache.0.lookup(path, 10, modified, || panic!("size and time settle it"), unparsed).unwrap();
+        assert_eq!(hit.0["@x"], "1");
+        assert_eq!(cache.counts(), [1, 1, 0, 0]);
+
+        // Touched with the same content, the entry is kept and gets the new time
+        cache.0.lookup(path, 10, touched, || Some(1), unparsed).unwrap();
+        cache.0.lookup(path, 10, touched, || panic!("the new time is stored"), unparsed).unwrap();
+        assert_eq!(cache.counts(), [3, 1, 0, 0]);
+
+        let changed = cache.0.lookup(path, 10, edited, || Some(2), || variables("2")).unwrap();
+        assert_eq!(changed.0["@x"], "2");
+        cache.0.lookup(path, 11, edited, || Some(2), || variables("3")).unwrap();
+        assert_eq!(cache.counts(), [3, 3, 2, 0]);
+
+        // Another file never gets this one's entry
+        let other = cache.0.lookup(Path::new("mod/other.txt"), 11, edited, || Some(2), || variables("4")).unwrap();
+        assert_eq!(other.0["@x"], "4");
+    }
+
+    #[test]
+    fn store_replaces_broken_entries_without_leaving_temporary_files() {
+        let cache = TempCache::new("store");
+        let path = Path::new("mod/common/scripted_variables/vars.txt");
+        let entry_path = cache.0.entry_path(path);
+        fs::write(&entry_path, b"not an entry").unwrap();
+
+        cache.0.lookup(path, 10, None, || Some(1), || variables("1")).unwrap();
+        assert_eq!(cache.counts(), [0, 1, 0, 1]);
+
+        let files: Vec<PathBuf> = fs::read_dir(&cache.0.dir).unwrap().map(|x| x.unwrap().path()).collect();
+        assert_eq!(files, vec![entry_path.clone()]);
+        let entry = cache.0.load::<Variables>(&entry_path).unwrap();
+        assert_eq!((entry.format, entry.path.as_path(), entry.size, entry.hash), (FORMAT, path, 10, 1));
+        assert_eq!(entry.value.0["@x"], "1");
+    }
+}
@@ -0,0 +1,635 @@
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use crate::diagnostics::{line_of, Diagnostic, Diagnostics, Related, Severity};
+use crate::localisation::{split_lines, FallbackChain, tokenize_line, Languages, LineDiagnostic, LocalisationLine};
+use crate::resolve::{Cycle, Resolver};
+use crate::{localisation_files_read, merge_localisations, Mod};
+
+/// A top level key and where it is defined, used to find duplicates across the files of one mod
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Definition {
+    pub key: String,
+    pub file: PathBuf,
+    pub line: usize,
+    pub span: Range<usize>,
+}
+
+impl Definition {
+    /// A warning pointing at the key of this definition
+    pub fn warning(&self, mod_name: &str, message: String) -> Diagnostic {
+        Diagnostic {
+            severity: Severity::Warning,
+            mod_name: Some(mod_name.to_string()),
+            file: self.file.clone(),
+            line: Some(self.line),
+            offset: Some(self.span.start),
+            length: Some(self.span.len()),
+            message,
+            label: None,
+            related: vec![],
+        }
+    }
+
+    pub fn related(&self, message: &str) -> Related {
+        Related {
+            file: self.file.clone(),
+            line: self.line,
+            offset: self.span.start,
+            length: self.span.len(),
+            message: message.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum ReferenceKind {
+    /// An `@variable` used as a value
+    Variable,
+
+    /// A technology listed in `prerequisites`
+    Prerequisite,
+}
+
+/// A name a technology file refers to, checked against everything loaded
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Reference {
+    pub kind: ReferenceKind,
+    pub name: String,
+    pub line: usize,
+    pub span: Range<usize>,
+}
+
+/// A problem of one file on its own, like bad markup, a line the loader skipped or an unknown encoding
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Finding {
+    pub severity: Severity,
+    pub line: usize,
+    pub span: Range<usize>,
+    pub message: String,
+    pub label: String,
+}
+
+impl Finding {
+    /// A line of a localisation file the parser could not read and left out
+    pub fn skipped(x: LineDiagnostic) -> Finding {
+        Finding {
+            severity: Severity::Error,
+            line: x.line,
+            span: x.span,
+            message: x.message,
+            label: "this line is left out, the rest of the file is read".to_string(),
+        }
+    }
+}
+
+/// Where things are in one file: the top level keys it defines, the names it refers to and
+/// the problems found in it alone. The loader builds it from the bytes it parses and caches it
+/// with the parse result, checks never read a file again.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct FileIndex {
+    pub file: PathBuf,
+    pub definitions: Vec<Definition>,
+    pub references: Vec<Reference>,
+    pub findings: Vec<Finding>,
+}
+
+impl FileIndex {
+    /// Indexes the technology file `file` holding `data`
+    pub fn of_technology_file(file: &Path, data: &[u8]) -> FileIndex {
+        let tokens = tokenize(data);
+
+        let mut index = FileIndex { file: file.to_path_buf(), ..Default::default() };
+        let mut depth: usize = 0;
+        let mut prerequisites_depth = None;
+        for (i, token) in tokens.iter().enumerate() {
+            let assigned = tokens.get(i + 1).is_some_and(|x| x.kind == TokenKind::Equals);
+            let opens = tokens.get(i + 2).is_some_and(|x| x.kind == TokenKind::Open);
+            let text = String::from_utf8_lossy(token.text);
+            let located = |kind| Reference {
+                kind,
+                name: text.to_string(),
+                line: line_of(data, token.span.start),
+                span: token.span.clone(),
+            };
+
+            match token.kind {
+                TokenKind::Open => depth += 1,
+                TokenKind::Close => {
+                    depth = depth.saturating_sub(1);
+                    if prerequisites_depth.is_some_and(|x| depth < x) {
+                        prerequisites_depth = None;
+                    }
+                }
+                TokenKind::Equals => {}
+                TokenKind::Word | TokenKind::Quoted => {
+                    if prerequisites_depth == Some(depth) {
+                        index.references.push(located(ReferenceKind::Prerequisite));
+                    }
+
+                    if depth == 0 && assigned {
+                        if opens && !text.starts_with('@') {
+                            index.definitions.push(Definition {
+                                key: text.to_string(),
+                                file: file.to_path_buf(),
+                                line: line_of(data, token.span.start),
+                                span: token.span.clone(),
+                            });
+                        }
+                    } else if token.kind == TokenKind::Word
+                        && text.starts_with('@')
+                        && !text.starts_with("@[")
+                        && !text.starts_with("@\\[")
+                    {
+                        index.references.push(located(ReferenceKind::Variable));
+                    }
+
+                    if text == "prerequisites" && assigned && opens {
+                        prerequisites_depth = Some(depth + 1);
+                    }
+                }
+            }
+        }
+        index
+    }
+
+    /// Indexes the localisation file `file` holding `data`, finding bad markup and a header not
+    /// matching the file name. Lines that do not parse are reported by the loader, see [`Finding::skipped`].
+    pub fn of_localisation_file(file: &Path, data: &[u8]) -> FileIndex {
+        let mut index = FileIndex { file: file.to_path_buf(), ..Default::default() };
+
+        // The header decides, the file name only stands in for a missing one like in the loader
+        let named = file.file_name().and_then(|x| x.to_str()).and_then(Languages::of_file_name);
+        for (i, (offset, line)) in split_lines(data).enumerate() {
+            let line = match std::str::from_utf8(line) {
+                Ok(line) => line,
+                Err(_) => continue,
+            };
+
+            match tokenize_line(line) {
+                Ok(Some(LocalisationLine::Header(x))) => {
+                    let language: Languages = x.parse().unwrap_or_default();
+                    if let Some(named) = named.as_ref().filter(|named| **named != language) {
+                        index.findings.push(Finding {
+                            severity: Severity::Warning,
+                            line: i + 1,
+                            span: offset + line.len() - line.trim_start().len()..offset + line.trim_end().len(),
+                            message: format!("Header l_{} does not match the file name", x),
+                            label: format!("the file name says {}, the keys are read as {}", named, language),
+                        });
+                    }
+                }
+                Ok(Some(LocalisationLine::Entry { key, key_span, value_span, .. })) => {
+                    index.findings.extend(check_markup(data, offset + value_span.start, &line[value_span]));
+                    index.definitions.push(Definition {
+                        key: key.to_string(),
+                        file: file.to_path_buf(),
+                        line: i + 1,
+                        span: offset + key_span.start..offset + key_span.end,
+                    });
+                }
+                Ok(None) | Err(_) => {}
+            }
+        }
+        index
+    }
+
+    /// A problem at `span` on `line` of this file
+    fn diagnostic(&self, severity: Severity, mod_name: &str, line: usize, span: Range<usize>, message: String, label: String) -> Diagnostic {
+        Diagnostic {
+            severity,
+            mod_name: Some(mod_name.to_string()),
+            file: self.file.clone(),
+            line: Some(line),
+            offset: Some(span.start),
+            length: Some(span.len()),
+            message,
+            label: Some(label),
+            related: vec![],
+        }
+    }
+
+    /// Reports the findings and the references to names not in `variables` or `technologies`
+    fn check(&self, mod_name: &str, variables: &HashSet<&str>, technologies: &HashSet<&str>, diagnostics: &Diagnostics) {
+        for x in &self.findings {
+            diagnostics.push(self.diagnostic(x.severity, mod_name, x.line, x.span.clone(), x.message.clone(), x.label.clone()));
+        }
+
+        for x in &self.references {
+            let (known, message, label) = match x.kind {
+                ReferenceKind::Variable => (
+                    variables.contains(x.name.as_str()),
+                    format!("Unresolved variable {}", x.name),
+                    "not defined in scripted_variables or any technology file",
+                ),
+                ReferenceKind::Prerequisite => (
+                    technologies.contains(x.name.as_str()),
+                    format!("Unknown prerequisite {}", x.name),
+                    "no loaded mod defines this technology",
+                ),
+            };
+            if !known {
+                diagnostics.push(self.diagnostic(Severity::Warning, mod_name, x.line, x.span.clone(), message, label.to_string()));
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum TokenKind {
+    Open,
+    Close,
+    Equals,
+    Word,
+    Quoted,
+}
+
+struct ScriptToken<'a> {
+    kind: TokenKind,
+    text: &'a [u8],
+    span: Range<usize>,
+}
+
+/// Just enough of the script syntax to find keys and values with their byte spans
+fn tokenize(data: &[u8]) -> Vec<ScriptToken<'_>> {
+    let mut tokens = vec![];
+    // A UTF-8 BOM is not part of the first key
+    let mut i = if data.starts_with(b"\xef\xbb\xbf") { 3 } else { 0 };
+    while i < data.len() {
+        let start = i;
+        let kind = match data[i] {
+            b'#' => {
+                while i < data.len() && data[i] != b'\n' {
+                    i += 1;
+                }
+                continue;
+            }
+            x if x.is_ascii_whitespace() => {
+                i += 1;
+                continue;
+            }
+            b'{' => TokenKind::Open,
+            b'}' => TokenKind::Close,
+            b'=' => TokenKind::Equals,
+            b'"' => {
+                i += 1;
+                while i < data.len() && data[i] != b'"' {
+                    i += if data[i] == b'\\' { 2 } else { 1 };
+                }
+                TokenKind::Quoted
+            }
+            _ => {
+                while i + 1 < data.len() && !data[i + 1].is_ascii_whitespace() && !b"{}=#\"".contains(&data[i + 1]) {
+                    i += 1;
+                }
+                TokenKind::Word
+            }
+        };
+        i = (i + 1).min(data.len());
+
+        let text = match kind {
+            TokenKind::Quoted => &data[start + 1..(i - 1).max(start + 1)],
+            _ => &data[start..i],
+        };
+        tokens.push(ScriptToken { kind, text, span: start..i });
+    }
+    tokens
+}
+
+
+/// Checks the markup of one localisation value, `offset` is where `value` starts in `data`
+fn check_markup(data: &[u8], offset: usize, value: &str) -> Vec<Finding> {
+    let end = offset + value.len();
+    let line = line_of(data, offset);
+    let mut findings = vec![];
+    let mut chars = value.char_indices();
+    while let Some((i, ch)) = chars.next() {
+        let start = offset + i;
+        let finding = |span: Range<usize>, message: String, label: String| Finding { severity: Severity::Warning, line, span, message, label };
+        let unterminated = |what: &str, close: char| {
+            finding(start..end, format!("Unterminated {}", what), format!("missing closing {}", close))
+        };
+
+        match ch {
+            '§' => match chars.next() {
+                Some((_, 'W' | 'T' | 'L' | 'P' | 'R' | 'S' | 'H' | 'Y' | 'G' | 'E' | 'B' | 'M' | '!')) => {}
+                Some((j, code)) => findings.push(finding(
+                    start..offset + j + code.len_utf8(),
+                    format!("Unknown colour code §{}", code),
+                    "expected one of W T L P R S H Y G E B M or !".to_string(),
+                )),
+                None => findings.push(finding(
+                    start..end,
+                    "Colour code at the end of the text".to_string(),
+                    "§ is not followed by a colour".to_string(),
+                )),
+            },
+            '$' | '£' | '[' => {
+                let close = if ch == '[' { ']' } else { ch };
+                match chars.by_ref().find(|(_, x)| *x == close) {
+                    Some(_) => {}
+                    None => findings.push(match ch {
+                        '$' => unterminated("$variable$", close),
+                        '£' => unterminated("£icon£", close),
+                        _ => unterminated("[command]", close),
+                    }),
+                }
+            }
+            _ => {}
+        }
+    }
+    findings
+}
+
+fn report_duplicates<'a, K: std::hash::Hash + Eq + Copy>(
+    mod_name: &str,
+    what: &str,
+    definitions: impl Iterator<Item = (K, &'a Definition)>,
+    diagnostics: &Diagnostics,
+) {
+    let mut first: HashMap<(K, &str), &Definition> = HashMap::new();
+    for (scope, x) in definitions {
+        match first.get(&(scope, x.key.as_str())) {
+            Some(previous) => {
+                diagnostics.push(
+                    x.warning(mod_name, format!("Duplicate {} {}", what, x.key))
+                        .with_label("defined again here".to_string())
+                        .with_related(previous.related("first defined here")),
+                );
+            }
+            None => {
+                first.insert((scope, x.key.as_str()), x);
+            }
+        }
+    }
+}
+
+/// Names every mod may refer to, loaded technologies and `@variables`
+pub struct Symbols<'a> {
+    variables: HashSet<&'a str>,
+    technologies: HashSet<&'a str>,
+}
+
+impl<'a> Symbols<'a> {
+    pub fn of(mods: &'a [Mod]) -> Symbols<'a> {
+        Symbols {
+            variables: mods.iter().flat_map(|x| x.variables.keys()).map(|x| x.as_str()).collect(),
+            technologies: mods.iter().flat_map(|x| x.technologies.keys()).map(|x| x.as_str()).collect(),
+        }
+    }
+}
+
+/// Checks the scripted variables and technology files of one mod, returns every technology definition in file order
+pub fn check_technologies(x: &Mod, symbols: &Symbols, diagnostics: &Diagnostics) -> Vec<Definition> {
+    let mod_name = x.descriptor.name.as_str();
+
+    x.script_files.par_iter().for_each(|index| index.check(mod_name, &symbols.variables, &symbols.technologies, diagnostics));
+    let definitions: Vec<Definition> = x.script_files.iter().flat_map(|index| index.definitions.iter().cloned()).collect();
+
+    report_duplicates(mod_name, "technology", definitions.iter().map(|x| ((), x)), diagnostics);
+    definitions
+}
+
+/// Checks the localisation files of one mod, returns every key defined with its language
+pub fn check_localisations(x: &Mod, diagnostics: &Diagnostics) -> Vec<(Languages, Definition)> {
+    let mod_name = x.descriptor.name.as_str();
+
+    let none = HashSet::new();
+    x.localisations.par_iter().for_each(|file| file.index.check(mod_name, &none, &none, diagnostics));
+
+    report_duplicates(
+        mod_name,
+        "localisation key",
+        x.localisations
+            .iter()
+            // Replacing keys is what the replace folder is for
+            .filter(|file| !file.replace)
+            .flat_map(|file| file.index.definitions.iter().map(move |x| (&file.language, x))),
+        diagnostics,
+    );
+    x.localisations
+        .iter()
+        .flat_map(|file| file.index.definitions.iter().map(move |x| (file.language.clone(), x.clone())))
+        .collect()
+}
+
+/// Where localisation keys are defined, with the name of the defining mod
+pub type KeyDefinitions<'a> = HashMap<(&'a Languages, &'a str), (&'a str, &'a Definition)>;
+
+/// Where the winning definition of every localisation key of `mods` is, picked like [`merge_localisations`] does
+pub fn key_definitions(mods: &[Mod]) -> KeyDefinitions<'_> {
+    localisation_files_read(mods)
+        .into_iter()
+        .flat_map(|(x, file)| file.index.definitions.iter().map(move |definition| (x, file, definition)))
+        .map(|(x, file, definition)| ((&file.language, definition.key.as_str()), (x.descriptor.name.as_str(), definition)))
+        .collect()
+}
+
+/// Reports localisation keys referring back to themselves through `$key$`, each cycle once and
+/// only if its keys are in `definitions`
+pub fn check_references(mods: &[Mod], fallback: &FallbackChain, definitions: &KeyDefinitions, diagnostics: &Diagnostics) {
+    let localisations = merge_localisations(mods);
+    let resolver = Resolver::new(&localisations, fallback);
+    let cycles: Vec<Cycle> = localisations
+        .par_iter()
+        .flat_map_iter(|(language, map)| map.iter().map(move |(key, x)| (language, *key, x.value)))
+        .filter(|(_, _, value)| value.contains('$'))
+        .flat_map_iter(|(language, key, value)| {
+            let mut cycles = vec![];
+            resolver.resolve(language, key, value, &mut cycles);
+            cycles
+        })
+        .collect();
+
+    let mut seen = HashSet::new();
+    for mut cycle in cycles {
+        // The same cycle is met from each of its keys, start at the smallest one to tell
+        let start = (0..cycle.len()).min_by_key(|x| &cycle[*x].1).unwrap_or(0);
+        cycle.rotate_left(start);
+        if !seen.insert(cycle.clone()) {
+            continue;
+        }
+
+        let path = cycle.iter().chain(cycle.first()).map(|(_, key)| key.as_str()).collect::<Vec<_>>().join(" -> ");
+        let (mod_name, first) = match definitions.get(&(&cycle[0].0, cycle[0].1.as_str())) {
+            Some(x) => x,
+            None => continue,
+        };
+        let mut diagnostic = first
+            .warning(mod_name, format!("Localisation keys refer to each other in a cycle: {}", path))
+            .with_label(format!("refers to ${}$", cycle[1 % cycle.len()].1));
+        for (i, (language, key)) in cycle.iter().enumerate().skip(1) {
+            if let Some((_, x)) = definitions.get(&(language, key.as_str())) {
+                diagnostic = diagnostic.with_related(x.related(&format!("refers to ${}$", cycle[(i + 1) % cycle.len()].1)));
+            }
+        }
+        diagnostics.push(diagnostic);
+    }
+}
+
+/// Cross-file checks run once everything is loaded, problems are reported as warnings pointing
+/// at the offending text: unresolved `@variables`, unknown prerequisites, keys defined twice
+/// within one mod, malformed localisation markup and localisation reference cycles.
+pub fn check_mods(mods: &[Mod], fallback: &FallbackChain, diagnostics: &Diagnostics) {
+    let symbols = Symbols::of(mods);
+    mods.par_iter().for_each(|x| {
+        check_technologies(x, &symbols, diagnostics);
+        check_localisations(x, diagnostics);
+    });
+
+    let definitions = key_definitions(mods);
+    check_references(mods, fallback, &definitions, diagnostics);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::descriptor::{ModDescriptor, ReplacedPaths};
+    use crate::localisation::read_localisations;
+    use crate::vfs::Memory;
+
+    /// A mod in `path` with the localisation files of `vfs` below it
+    fn mod_with_localisations(vfs: &Memory, path: &str, name: &str) -> Mod {
+        let mut x = Mod::empty(path.into(), path.to_string(), ModDescriptor::named(name));
+        x.localisations = read_localisations(vfs, path, &ReplacedPaths::default(), Diagnostics::default().reporter(name)).unwrap();
+        x
+    }
+
+    fn messages(diagnostics: &Diagnostics) -> Vec<String> {
+        diagnostics.to_vec().into_iter().map(|x| x.message).collect()
+    }
+
+    #[test]
+    fn tokenize_splits_words_strings_and_braces_with_their_spans() {
+        let data = "\u{feff}a = { \"b c\" } # d = e\n@x=1".as_bytes();
+        let tokens: Vec<(TokenKind, &str, Range<usize>)> = tokenize(data)
+            .into_iter()
+            .map(|x| (x.kind, std::str::from_utf8(x.text).unwrap(), x.span))
+            .collect();
+
+        assert_eq!(tokens, vec![
+            (TokenKind::Word, "a", 3..4),
+            (TokenKind::Equals, "=", 5..6),
+            (TokenKind::Open, "{", 7..8),
+            (TokenKind::Quoted, "b c", 9..14),
+            (TokenKind::Close, "}", 15..16),
+            (TokenKind::Word, "@x", 25..27),
+            (TokenKind::Equals, "=", 27..28),
+            (TokenKind::Word, "1", 28..29),
+        ]);
+    }
+
+    #[test]
+    fn technology_files_index_top_level_keys_variables_and_prerequisites() {
+        let data = [
+            "@cost = 10",
+            "tech_a = {",
+            "    cost = @cost",
+            "    weight = @[ cost * 2 ]",
+            "    prerequisites = { \"tech_b\" tech_c }",
+            "    potential = { has_technology = tech_d }",
+            "}",
+        ].join("\n");
+        let index = FileIndex::of_technology_file(Path::new("t.txt"), data.as_bytes());
+
+        let definitions: Vec<(&str, usize)> = index.definitions.iter().map(|x| (x.key.as_str(), x.line)).collect();
+        assert_eq!(definitions, vec![("tech_a", 2)]);
+        let references: Vec<(ReferenceKind, &str, usize)> = index.references.iter().map(|x| (x.kind, x.name.as_str(), x.line)).collect();
+        assert_eq!(references, vec![
+            (ReferenceKind::Variable, "@cost", 3),
+            (ReferenceKind::Prerequisite, "tech_b", 5),
+            (ReferenceKind::Prerequisite, "tech_c", 5),
+        ]);
+        assert_eq!(&data.as_bytes()[index.references[0].span.clone()], b"@cost");
+    }
+
+    #[test]
+    fn localisation_files_index_keys_and_a_header_not_matching_the_file_name() {
+        let data = "l_german:\n key_a:0 \"A\"\n key_b: \"§Zb\"\n";
+        let index = FileIndex::of_localisation_file(Path::new("x_l_english.yml"), data.as_bytes());
+
+        let definitions: Vec<(&str, usize, &str)> =
+            index.definitions.iter().map(|x| (x.key.as_str(), x.line, &data[x.span.clone()])).collect();
+        assert_eq!(definitions, vec![("key_a", 2, "key_a"), ("key_b", 3, "key_b")]);
+        let findings: Vec<(&str, usize, &str)> = index.findings.iter().map(|x| (x.message.as_str(), x.line, &data[x.span.clone()])).collect();
+        assert_eq!(findings, vec![
+            ("Header l_german does not match the file name", 1, "l_german:"),
+            ("Unknown colour code §Z", 3, "§Z"),
+        ]);
+    }
+
+    #[test]
+    fn check_markup_finds_bad_colours_and_unterminated_markup() {
+        let check = |value: &str| -> Vec<(String, String)> {
+            let data = format!(" key:0 \"{}\"", value);
+            check_markup(data.as_bytes(), 8, value)
+                .into_iter()
+                .map(|x| (x.message, data[x.span].to_string()))
+                .collect()
+        };
+
+        assert_eq!(check("§Yfine§! $var$ £energy£ [Root.GetName]"), vec![]);
+        assert_eq!(check("§Qbad"), vec![("Unknown colour code §Q".to_string(), "§Q".to_string())]);
+        assert_eq!(check("ends §"), vec![("Colour code at the end of the text".to_string(), "§".to_string())]);
+        assert_eq!(check("a $var"), vec![("Unterminated $variable$".to_string(), "$var".to_string())]);
+        assert_eq!(check("[Root"), vec![("Unterminated [command]".to_string(), "[Root".to_string())]);
+    }
+
+    #[test]
+    fn duplicate_keys_are_reported_per_language_but_not_in_replace_folders() {
+        let vfs = Memory::default()
+            .with_file("m/localisation/english/a_l_english.yml", "l_english:\n key:0 \"A\"\n")
+            .with_file("m/localisation/english/b_l_english.yml", "l_english:\n key:0 \"B\"\n")
+            .with_file("m/localisation/german/a_l_german.yml", "l_german:\n key:0 \"A\"\n")
+            .with_file("m/localisation/replace/c_l_english.yml", "l_english:\n key:0 \"C\"\n");
+        let x = mod_with_localisations(&vfs, "m", "Mod");
+        let diagnostics = Diagnostics::default();
+
+        let definitions = check_localisations(&x, &diagnostics);
+
+        assert_eq!(definitions.len(), 4);
+        assert_eq!(messages(&diagnostics), vec!["Duplicate localisation key key"]);
+        let duplicate = &diagnostics.to_vec()[0];
+        assert_eq!(duplicate.file, Path::new("m/localisation/english/b_l_english.yml"));
+        assert_eq!(duplicate.related[0].file, Path::new("m/localisation/english/a_l_english.yml"));
+    }
+
+    #[test]
+    fn key_definitions_point_at_the_definition_the_merge_picks() {
+        let vfs = Memory::default()
+            .with_file("a/localisation/english/same_l_english.yml", "l_english:\n kept:0 \"A\"\n dropped:0 \"A\"\n")
+            .with_file("a/localisation/replace/r_l_english.yml", "l_english:\n replaced:0 \"A\"\n")
+            .with_file("b/localisation/english/same_l_english.yml", "l_english:\n kept:0 \"B\"\n")
+            .with_file("b/localisation/english/other_l_english.yml", "l_english:\n replaced:0 \"B\"\n");
+        let mods = [mod_with_localisations(&vfs, "a", "A"), mod_with_localisations(&vfs, "b", "B")];
+
+        let definitions = key_definitions(&mods);
+
+        let winner = |key: &str| definitions.get(&(&Languages::English, key)).map(|(name, x)| (*name, x.file.to_string_lossy().to_string()));
+        // The later file at the same path is read instead of the earlier one as a whole
+        assert_eq!(winner("kept"), Some(("B", "b/localisation/english/same_l_english.yml".to_string())));
+        assert_eq!(winner("dropped"), None);
+        // Replace folders win over later mods
+        assert_eq!(winner("replaced"), Some(("A", "a/localisation/replace/r_l_english.yml".to_string())));
+        let merged = merge_localisations(&mods);
+        assert_eq!(merged[&Languages::English]["kept"].value, "B");
+        assert_eq!(merged[&Languages::English]["replaced"].value, "A");
+    }
+
+    #[test]
+    fn reference_cycles_are_reported_once_at_their_smallest_key() {
+        let vfs = Memory::default()
+            .with_file("m/localisation/english/a_l_english.yml", "l_english:\n b:0 \"$c$\"\n c:0 \"$a$\"\n a:0 \"$b$\"\n fine:0 \"$a$\"\n");
+        let mods = [mod_with_localisations(&vfs, "m", "Mod")];
+        let diagnostics = Diagnostics::default();
+
+        check_references(&mods, &FallbackChain::default(), &key_definitions(&mods), &diagnostics);
+
+        assert_eq!(messages(&diagnostics), vec!["Localisation keys refer to each other in a cycle: a -> b -> c -> a"]);
+        let cycle = &diagnostics.to_vec()[0];
+        assert_eq!(cycle.line, Some(4));
+        assert_eq!(cycle.related.iter().map(|x| x.line).collect::<Vec<_>>(), vec![2, 3]);
+    }
+}
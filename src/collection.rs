@@ -1,10 +1,10 @@
 use anyhow::anyhow;
-use serde::de::IntoDeserializer;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use std::collections::HashMap;
 use std::fmt::{Display, Formatter};
 use std::path::{Path, PathBuf};
+use crate::vfs::Vfs;
 
 #[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
 pub struct IronyModCollection {
@@ -21,12 +21,16 @@ pub struct IronyModCollection {
     pub mod_registry_ids: Vec<String>,
 }
 
+/// Workshop id of a launcher registry id like `mod/ugc_1234.mod`, local mods have none
+fn workshop_id(registry_id: &str) -> Option<u64> {
+    registry_id.trim_start_matches("mod/ugc_").trim_end_matches(".mod").parse().ok()
+}
+
 impl IronyModCollection {
     pub fn get_mod_ids(&self) -> Vec<u64> {
         self.mod_registry_ids
             .iter()
-            .map(|x| x.trim_start_matches("mod/ugc_").trim_end_matches(".mod"))
-            .filter_map(|x| x.parse::<u64>().ok())
+            .filter_map(|x| workshop_id(x))
             .collect()
     }
 }
@@ -64,7 +68,7 @@ pub fn parse_irony_collections<P: AsRef<Path>>(
                     })
                 })
                 .and_then(|v| v["ExecutableLocation"].as_str())
-                .map(|v| Path::new(v))
+                .map(Path::new)
                 .and_then(|v| v.parent())
                 .and_then(|v| v.parent())
                 .and_then(|v| v.parent())
@@ -88,7 +92,7 @@ pub fn parse_irony_collections<P: AsRef<Path>>(
             })
             .and_then(|x| {
                 x["Value"].as_array().map(|v| {
-                    v.into_iter()
+                    v.iter()
                         .filter_map(|y| {
                             serde_json::from_value::<IronyModCollection>(y.clone()).ok()
                         })
@@ -143,3 +147,316 @@ pub fn parse_paradox_launcher_load_order<P: AsRef<Path>>(
         .filter_map(|x| registry.get(x).cloned())
         .collect())
 }
+
+/// Mod of a playset in the Paradox launcher database
+#[derive(Debug, PartialEq, Serialize, Clone)]
+pub struct PlaysetMod {
+    pub name: String,
+    pub enabled: bool,
+    pub position: i64,
+
+    /// Content folder as the launcher last saw it
+    pub dir_path: Option<String>,
+
+    /// Launcher file relative to the user folder, e.g. `mod/ugc_1234.mod`
+    pub game_registry_id: Option<String>,
+}
+
+impl PlaysetMod {
+    /// What [crate::locate_mods] reads for this mod, its folder in `vfs` or else its launcher file
+    pub fn source<V: Vfs>(&self, vfs: &V, user_folder: &Path) -> Option<String> {
+        self.dir_path
+            .clone()
+            .filter(|x| vfs.is_dir(Path::new(x)))
+            .or_else(|| self.game_registry_id.as_ref().map(|x| user_folder.join(x).to_string_lossy().to_string()))
+    }
+}
+
+/// Playset of the Paradox launcher, `mods` are sorted by position
+#[derive(Debug, PartialEq, Serialize, Clone)]
+pub struct Playset {
+    pub id: String,
+    pub name: String,
+    pub is_active: bool,
+    pub mods: Vec<PlaysetMod>,
+}
+
+impl Playset {
+    /// Sources of the enabled mods in load order
+    pub fn sources<V: Vfs>(&self, vfs: &V, user_folder: &Path) -> Vec<String> {
+        self.mods.iter().filter(|x| x.enabled).filter_map(|x| x.source(vfs, user_folder)).collect()
+    }
+}
+
+impl Display for Playset {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({} mods)", self.name, self.mods.iter().filter(|x| x.enabled).count())?;
+        if self.is_active {
+            f.write_str(" [active]")?;
+        }
+        Ok(())
+    }
+}
+
+/// Older launchers store booleans and positions as text
+fn to_i64(value: rusqlite::types::Value) -> Option<i64> {
+    match value {
+        rusqlite::types::Value::Integer(x) => Some(x),
+        rusqlite::types::Value::Text(x) => x.trim().parse().ok(),
+        _ => None,
+    }
+}
+
+/// Every playset in `launcher-v2.sqlite`, the database of current Paradox launchers
+pub fn parse_paradox_launcher_playsets<P: AsRef<Path>>(path: P) -> anyhow::Result<Vec<Playset>> {
+    let connection = rusqlite::Connection::open_with_flags(path.as_ref(), rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    read_playsets(&connection)
+}
+
+/// Every playset in the launcher database open in `connection`, sorted by name
+fn read_playsets(connection: &rusqlite::Connection) -> anyhow::Result<Vec<Playset>> {
+    let mut playsets: Vec<Playset> = connection
+        .prepare("SELECT id, name, isActive FROM playsets ORDER BY name")?
+        .query_map([], |row| {
+            Ok(Playset {
+                id: row.get(0)?,
+                name: row.get(1)?,
+                is_active: to_i64(row.get(2)?).unwrap_or_default() != 0,
+                mods: vec![],
+            })
+        })?
+        .collect::<Result<_, _>>()?;
+
+    let mut statement = connection.prepare(
+        "SELECT playsets_mods.playsetId, playsets_mods.enabled, playsets_mods.position, mods.displayName, mods.dirPath, mods.gameRegistryId
+         FROM playsets_mods JOIN mods ON mods.id = playsets_mods.modId",
+    )?;
+    let mods = statement.query_map([], |row| {
+        let playset: String = row.get(0)?;
+        Ok((
+            playset,
+            PlaysetMod {
+                enabled: to_i64(row.get(1)?).unwrap_or(1) != 0,
+                position: to_i64(row.get(2)?).unwrap_or(i64::MAX),
+                name: row.get::<_, Option<String>>(3)?.unwrap_or_default(),
+                dir_path: row.get(4)?,
+                game_registry_id: row.get(5)?,
+            },
+        ))
+    })?;
+
+    let mut by_id: HashMap<String, Vec<PlaysetMod>> = HashMap::new();
+    for x in mods {
+        let (playset, x) = x?;
+        by_id.entry(playset).or_default().push(x);
+    }
+    for playset in &mut playsets {
+        playset.mods = by_id.remove(&playset.id).unwrap_or_default();
+        playset.mods.sort_by_key(|x| x.position);
+    }
+
+    Ok(playsets)
+}
+
+/// Playset exported by the Paradox launcher
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ExportedPlayset {
+    #[serde(default)]
+    name: String,
+    mods: Vec<ExportedPlaysetMod>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ExportedPlaysetMod {
+    #[serde(default)]
+    display_name: String,
+
+    #[serde(default = "enabled_by_default")]
+    enabled: bool,
+
+    #[serde(default)]
+    position: i64,
+    steam_id: Option<String>,
+}
+
+fn enabled_by_default() -> bool {
+    true
+}
+
+/// Collection exported by Irony Mod Manager, the `exported.json` of its zip files
+#[derive(Debug, Deserialize)]
+struct ExportedIronyCollection {
+    #[serde(alias = "Name", default)]
+    name: String,
+
+    #[serde(alias = "Mods")]
+    mods: Vec<String>,
+
+    /// Display names in the order of `mods`, only written by newer versions
+    #[serde(alias = "ModNames", default)]
+    mod_names: Vec<String>,
+}
+
+/// Playset someone else shared, resolved against the local workshop folder
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImportedPlayset {
+    pub name: String,
+
+    /// Installed mod folders in load order
+    pub sources: Vec<String>,
+
+    /// Mods of the playset without a folder in the workshop, by name and id
+    pub missing: Vec<String>,
+}
+
+impl ImportedPlayset {
+    /// Looks up `(name, workshop id)` pairs in load order in `workshop_path` in `vfs`
+    fn resolve<V: Vfs>(vfs: &V, name: String, mods: impl Iterator<Item = (String, Option<String>)>, workshop_path: &Path) -> ImportedPlayset {
+        let mut playset = ImportedPlayset { name, sources: vec![], missing: vec![] };
+        for (name, id) in mods {
+            match id.map(|x| workshop_path.join(x.trim())).filter(|x| vfs.is_dir(x)) {
+                Some(path) => playset.sources.push(path.to_string_lossy().to_string()),
+                None => playset.missing.push(name),
+            }
+        }
+        playset
+    }
+}
+
+/// Reads a playset exported by the Paradox launcher or a collection exported by Irony Mod Manager,
+/// as its zip or its `exported.json`, and finds the mods in `workshop_path`
+pub fn import_playset<V: Vfs, P: AsRef<Path>>(vfs: &V, path: P, workshop_path: &Path) -> anyhow::Result<ImportedPlayset> {
+    let path = path.as_ref();
+    let data = vfs.read(path)?;
+    let text = if path.extension().is_some_and(|x| x.eq_ignore_ascii_case("zip")) {
+        let mut archive = zip::ZipArchive::new(std::io::Cursor::new(data))?;
+        let name = archive
+            .file_names()
+            .filter(|x| x.ends_with(".json"))
+            .min_by_key(|x| !x.ends_with("exported.json"))
+            .map(|x| x.to_string())
+            .ok_or(anyhow!("{} has no exported collection", path.display()))?;
+        archive.by_name(&name).map(std::io::read_to_string)??
+    } else {
+        String::from_utf8(data)?
+    };
+
+    import_exported(vfs, serde_json::from_str(&text)?, workshop_path)
+}
+
+/// Resolves an exported playset or collection against the workshop folder in `vfs`
+fn import_exported<V: Vfs>(vfs: &V, value: Value, workshop_path: &Path) -> anyhow::Result<ImportedPlayset> {
+    // Irony writes PascalCase keys, the launcher camelCase ones
+    if value.get("Mods").is_some() {
+        let collection: ExportedIronyCollection = serde_json::from_value(value)?;
+        let mods = collection.mods.iter().enumerate().map(|(i, x)| {
+            let name = collection.mod_names.get(i).map_or_else(|| x.clone(), |name| format!("{} ({})", name, x));
+            (name, workshop_id(x).map(|x| x.to_string()))
+        });
+        Ok(ImportedPlayset::resolve(vfs, collection.name.clone(), mods, workshop_path))
+    } else {
+        let mut playset: ExportedPlayset = serde_json::from_value(value)?;
+        playset.mods.sort_by_key(|x| x.position);
+        let mods = playset.mods.into_iter().filter(|x| x.enabled).map(|x| match &x.steam_id {
+            Some(id) => (format!("{} ({})", x.display_name, id), x.steam_id),
+            None => (x.display_name, None),
+        });
+        Ok(ImportedPlayset::resolve(vfs, playset.name, mods, workshop_path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use crate::vfs::Memory;
+
+    /// Launcher database with the tables and columns the reader uses
+    fn launcher_database() -> rusqlite::Connection {
+        let connection = rusqlite::Connection::open_in_memory().unwrap();
+        connection
+            .execute_batch(
+                "CREATE TABLE playsets (id TEXT PRIMARY KEY, name TEXT, isActive);
+                 CREATE TABLE mods (id TEXT PRIMARY KEY, displayName TEXT, dirPath TEXT, gameRegistryId TEXT);
+                 CREATE TABLE playsets_mods (playsetId TEXT, modId TEXT, enabled, position);
+                 INSERT INTO playsets VALUES ('p1', 'Zeta', 1), ('p2', 'Alpha', '0');
+                 INSERT INTO mods VALUES
+                     ('m1', 'First', '/workshop/111', 'mod/ugc_111.mod'),
+                     ('m2', 'Second', NULL, 'mod/local.mod'),
+                     ('m3', 'Third', '/workshop/333', NULL);
+                 INSERT INTO playsets_mods VALUES
+                     ('p1', 'm1', 1, '2'),
+                     ('p1', 'm2', 0, 1),
+                     ('p1', 'm3', '1', 0),
+                     ('p2', 'm1', 1, 0);",
+            )
+            .unwrap();
+        connection
+    }
+
+    #[test]
+    fn reads_playsets_in_name_and_mods_in_position_order() {
+        let playsets = read_playsets(&launcher_database()).unwrap();
+
+        let names: Vec<(&str, bool)> = playsets.iter().map(|x| (x.name.as_str(), x.is_active)).collect();
+        assert_eq!(names, [("Alpha", false), ("Zeta", true)]);
+
+        let mods: Vec<(&str, bool, i64)> = playsets[1].mods.iter().map(|x| (x.name.as_str(), x.enabled, x.position)).collect();
+        assert_eq!(mods, [("Third", true, 0), ("Second", false, 1), ("First", true, 2)]);
+        assert_eq!(playsets[1].mods[1].game_registry_id.as_deref(), Some("mod/local.mod"));
+        assert_eq!(playsets[0].mods.len(), 1);
+    }
+
+    #[test]
+    fn sources_leave_out_disabled_mods() {
+        let playsets = read_playsets(&launcher_database()).unwrap();
+
+        // The folder of First is gone, its launcher file stands in for it
+        let vfs = Memory::default().with_file("/workshop/333/descriptor.mod", "name=\"Third\"");
+        assert_eq!(playsets[1].sources(&vfs, Path::new("user")), ["/workshop/333", "user/mod/ugc_111.mod"]);
+    }
+
+    /// Workshop folder holding mods 111 and 222
+    fn workshop() -> Memory {
+        Memory::default()
+            .with_file("workshop/111/descriptor.mod", "name=\"A\"")
+            .with_file("workshop/222/descriptor.mod", "name=\"B\"")
+    }
+
+    #[test]
+    fn imports_launcher_playsets_in_position_order() {
+        let exported = json!({
+            "name": "Shared",
+            "mods": [
+                { "displayName": "B", "position": 1, "steamId": "222" },
+                { "displayName": "A", "position": 0, "steamId": "111" },
+                { "displayName": "Off", "position": 2, "enabled": false, "steamId": "111" },
+                { "displayName": "Local", "position": 3 },
+                { "displayName": "Gone", "position": 4, "steamId": "444" },
+            ],
+        });
+
+        assert_eq!(import_exported(&workshop(), exported, Path::new("workshop")).unwrap(), ImportedPlayset {
+            name: "Shared".to_string(),
+            sources: vec!["workshop/111".to_string(), "workshop/222".to_string()],
+            missing: vec!["Local".to_string(), "Gone (444)".to_string()],
+        });
+    }
+
+    #[test]
+    fn imports_irony_collections_in_listed_order() {
+        let exported = json!({
+            "Name": "Irony",
+            "Mods": ["mod/ugc_222.mod", "mod/ugc_111.mod", "mod/ugc_444.mod", "mod/local.mod"],
+            "ModNames": ["B", "A", "Gone"],
+        });
+
+        assert_eq!(import_exported(&workshop(), exported, Path::new("workshop")).unwrap(), ImportedPlayset {
+            name: "Irony".to_string(),
+            sources: vec!["workshop/222".to_string(), "workshop/111".to_string()],
+            missing: vec!["Gone (mod/ugc_444.mod)".to_string(), "mod/local.mod".to_string()],
+        });
+    }
+}
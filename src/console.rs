@@ -1,12 +1,12 @@
-use std::ffi::OsString;
 use std::path::Path;
 use anyhow::anyhow;
-use inquire::error::InquireResult;
-use inquire::{Confirm, Select};
+use inquire::{Confirm, MultiSelect, Select};
 use itertools::Itertools;
-use crate::collection::{parse_irony_collections, parse_paradox_launcher_load_order, parse_paradox_launcher_registry};
+use crate::descriptor::{launcher_files, locate_mod};
+use crate::vfs::Vfs;
+use crate::collection::{parse_irony_collections, parse_paradox_launcher_load_order, parse_paradox_launcher_playsets, parse_paradox_launcher_registry};
 
-pub fn query() -> anyhow::Result<Vec<String>> {
+pub fn query<V: Vfs>(vfs: &V) -> anyhow::Result<Vec<String>> {
     let user_folder = std::env::var("USERPROFILE")?;
     let app_data = std::env::var("APPDATA")?;
 
@@ -23,7 +23,7 @@ pub fn query() -> anyhow::Result<Vec<String>> {
             let (workshop_path, collections) = parse_irony_collections(irony_path.join(irony_db))?;
 
             let irony_db = Select::new("Select a Stellaris mod collection", collections.clone())
-                .with_starting_cursor(collections.iter().find_position(|x| x.is_selected == true).map(|(i, _)| i).unwrap_or_default())
+                .with_starting_cursor(collections.iter().find_position(|x| x.is_selected).map(|(i, _)| i).unwrap_or_default())
                 .prompt()?;
 
             return if let Some(workshop_path) = workshop_path {
@@ -42,8 +42,39 @@ pub fn query() -> anyhow::Result<Vec<String>> {
     if game_data_path.is_dir() {
         println!("Found Paradox launcher data folder in {}", game_data_path.display());
 
+        // Current launchers keep every playset in a database, game_data.json only has the last load order
+        let database = game_data_path.join("launcher-v2.sqlite");
+        let playsets = if database.is_file() { parse_paradox_launcher_playsets(&database)? } else { vec![] };
+        if !playsets.is_empty() {
+            let playset = Select::new("Select a Stellaris playset", playsets.clone())
+                .with_starting_cursor(playsets.iter().position(|x| x.is_active).unwrap_or_default())
+                .prompt()?;
+            let sources = playset.sources(vfs, &game_data_path);
+
+            println!("Parsing playset {} with {} mods", playset.name, sources.len());
+            return Ok(sources);
+        }
+
         let ans = Confirm::new("Do you want to use Paradox Launcher's current load order?").with_default(true).prompt()?;
 
+        if !ans {
+            // Every mod the launcher knows, including local ones that are not on the workshop
+            let mut files = vec![];
+            let mut names = vec![];
+            for file in launcher_files(vfs, &game_data_path)? {
+                match locate_mod(vfs, &file) {
+                    Ok(x) => {
+                        names.push(format!("{} ({})", x.descriptor.name, x.id));
+                        files.push(file);
+                    }
+                    Err(e) => println!("Skipping {}", e),
+                }
+            }
+
+            let selected = MultiSelect::new("Select mods, they load in the listed order", names).raw_prompt()?;
+            return Ok(selected.into_iter().map(|x| files[x.index].to_string_lossy().to_string()).collect());
+        }
+
         let registry = parse_paradox_launcher_registry(game_data_path.join("mods_registry.json"))?;
         let collection = parse_paradox_launcher_load_order(game_data_path.join("game_data.json"), &registry)?;
 
@@ -0,0 +1,59 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use serde::Serialize;
+use crate::localisation::Languages;
+use crate::{Mod, Provided};
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct LanguageCoverage {
+    /// Technologies with a name in the language itself, fallbacks are not counted
+    pub names: usize,
+    pub descriptions: usize,
+}
+
+/// How much of the technologies of one mod is translated, per language
+#[derive(Debug, Clone, Serialize)]
+pub struct Coverage {
+    #[serde(rename = "mod")]
+    pub mod_name: String,
+
+    /// Stable id of the mod, the workshop id when it has one
+    pub id: String,
+    pub technologies: usize,
+    pub languages: BTreeMap<String, LanguageCoverage>,
+}
+
+/// Coverage of every mod in `mods` against the merged keys, so a translation provided by
+/// another mod counts for the mod defining the technology. Every language the game ships is
+/// listed, community languages only when some mod has them.
+pub fn coverage(mods: &[Mod], localisations: &HashMap<Languages, BTreeMap<&str, Provided>>) -> Vec<Coverage> {
+    let languages: BTreeSet<&Languages> = Languages::KNOWN
+        .iter()
+        .chain(localisations.keys())
+        .filter(|x| **x != Languages::Default)
+        .collect();
+
+    mods.iter()
+        .map(|x| {
+            let languages = languages
+                .iter()
+                .map(|language| {
+                    let map = localisations.get(*language);
+                    let defined = |keys: Vec<String>| map.is_some_and(|map| keys.iter().any(|x| map.contains_key(x.as_str())));
+                    let mut coverage = LanguageCoverage::default();
+                    for (id, data) in &x.technologies {
+                        coverage.names += defined(data.name_keys(id)) as usize;
+                        coverage.descriptions += defined(data.desc_keys(id)) as usize;
+                    }
+                    (language.to_string(), coverage)
+                })
+                .collect();
+
+            Coverage {
+                mod_name: x.descriptor.name.clone(),
+                id: x.id.clone(),
+                technologies: x.technologies.len(),
+                languages,
+            }
+        })
+        .collect()
+}
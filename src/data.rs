@@ -1,16 +1,14 @@
 use crate::localisation::{Languages, Text};
-use jomini::{JominiDeserialize, TextTape};
+use jomini::JominiDeserialize;
 use serde::de::{Error, MapAccess, SeqAccess, Visitor};
 use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fmt;
 use std::fmt::{Formatter};
 use std::hash::{Hash, Hasher};
 use std::marker::PhantomData;
 use std::rc::Rc;
 use std::str::FromStr;
-use anyhow::anyhow;
-use jomini::text::Operator;
 use serde_json::Value;
 
 #[derive(Debug, PartialEq, Clone, Eq)]
@@ -48,9 +46,9 @@ impl AsRef<str> for ResearchArea {
     }
 }
 
-impl ToString for ResearchArea {
-    fn to_string(&self) -> String {
-        String::from(self.as_ref())
+impl fmt::Display for ResearchArea {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_ref())
     }
 }
 
@@ -95,17 +93,19 @@ impl<'de> Deserialize<'de> for ResearchArea {
     }
 }
 
+/// Not read yet, see the `modifier` field left out of [TechnologyData]
+#[allow(dead_code)]
 #[derive(PartialEq, Debug, Default, Clone, Serialize)]
 pub struct Modifier {
     pub factor: Option<String>,
 
-    pub not: Option<Vec<Box<Modifier>>>,
+    pub not: Option<Vec<Modifier>>,
 
-    pub or: Option<Vec<Box<Modifier>>>,
+    pub or: Option<Vec<Modifier>>,
 
-    pub nand: Option<Vec<Box<Modifier>>>,
+    pub nand: Option<Vec<Modifier>>,
 
-    pub and: Option<Vec<Box<Modifier>>>,
+    pub and: Option<Vec<Modifier>>,
 
     pub modifiers: HashMap<String, String>
 }
@@ -192,10 +192,41 @@ pub struct TechnologyData {
     #[jomini(default)]
     pub start_tech: bool,
 
+    /// Localisation key of the name when it is not the id of the technology
+    pub name: Option<String>,
+
+    /// Localisation key of the description when it is not `<id>_desc`
+    pub desc: Option<String>,
+
     //#[jomini(duplicated, default)]
     //pub modifier: Vec<Modifier>,
 }
 
+impl TechnologyData {
+    /// Keys the name of technology `id` is read from, in order: the `name` override, `<id>`, `<id>_name`
+    pub fn name_keys(&self, id: &str) -> Vec<String> {
+        self.name.iter().cloned().chain([id.to_string(), format!("{}_name", id)]).collect()
+    }
+
+    /// Keys the description of technology `id` is read from, in order: the `desc` override, `<id>_desc`
+    pub fn desc_keys(&self, id: &str) -> Vec<String> {
+        self.desc.iter().cloned().chain([format!("{}_desc", id)]).collect()
+    }
+
+    /// Name and description of technology `id` from the keys of one language, `None` if it has neither
+    pub fn localise<V: AsRef<str>>(&self, id: &str, map: &BTreeMap<&str, V>) -> Option<Text> {
+        let find = |keys: Vec<String>| keys.iter().find_map(|x| map.get(x.as_str())).map(|x| x.as_ref().to_string());
+        let name = find(self.name_keys(id));
+        let description = find(self.desc_keys(id));
+
+        Some(Text {
+            value: name.clone().or_else(|| description.clone())?,
+            name,
+            description,
+        })
+    }
+}
+
 #[derive(Debug, Default, Serialize, Clone, Eq)]
 pub struct Technology {
     pub modid: String,
@@ -338,3 +369,47 @@ impl<T> FromStr for StringOrStruct<T> {
         Ok(StringOrStruct::Str(s.to_string()))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(value: &str, name: Option<&str>, description: Option<&str>) -> Text {
+        Text { value: value.to_string(), name: name.map(str::to_string), description: description.map(str::to_string) }
+    }
+
+    #[test]
+    fn keys_start_with_the_override_then_follow_the_game_conventions() {
+        let plain = TechnologyData::default();
+        assert_eq!(plain.name_keys("tech_x"), vec!["tech_x", "tech_x_name"]);
+        assert_eq!(plain.desc_keys("tech_x"), vec!["tech_x_desc"]);
+
+        let overridden = TechnologyData { name: Some("shared_name".to_string()), desc: Some("shared_desc".to_string()), ..Default::default() };
+        assert_eq!(overridden.name_keys("tech_x"), vec!["shared_name", "tech_x", "tech_x_name"]);
+        assert_eq!(overridden.desc_keys("tech_x"), vec!["shared_desc", "tech_x_desc"]);
+    }
+
+    #[test]
+    fn localise_takes_the_first_key_defined() {
+        let map = BTreeMap::from([("shared_name", "Shared"), ("tech_x", "X"), ("tech_x_name", "X name"), ("tech_x_desc", "About X")]);
+
+        assert_eq!(TechnologyData::default().localise("tech_x", &map), Some(text("X", Some("X"), Some("About X"))));
+        let overridden = TechnologyData { name: Some("shared_name".to_string()), desc: Some("missing_desc".to_string()), ..Default::default() };
+        assert_eq!(overridden.localise("tech_x", &map), Some(text("Shared", Some("Shared"), Some("About X"))));
+
+        let suffixed = BTreeMap::from([("tech_x_name", "X name")]);
+        assert_eq!(TechnologyData::default().localise("tech_x", &suffixed), Some(text("X name", Some("X name"), None)));
+    }
+
+    #[test]
+    fn localise_falls_back_to_the_description_in_each_language() {
+        let english = BTreeMap::from([("tech_x", "Lasers"), ("tech_x_desc", "Pew")]);
+        let german = BTreeMap::from([("tech_x_desc", "Piu")]);
+        let french = BTreeMap::from([("tech_y", "Autre")]);
+        let data = TechnologyData::default();
+
+        assert_eq!(data.localise("tech_x", &english), Some(text("Lasers", Some("Lasers"), Some("Pew"))));
+        assert_eq!(data.localise("tech_x", &german), Some(text("Piu", None, Some("Piu"))));
+        assert_eq!(data.localise("tech_x", &french), None);
+    }
+}
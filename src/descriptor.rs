@@ -0,0 +1,203 @@
+use std::collections::BTreeSet;
+use std::io;
+use std::path::{Path, PathBuf};
+use jomini::JominiDeserialize;
+use serde::Serialize;
+use xxhash_rust::xxh3::xxh3_64;
+use crate::diagnostics::ParseError;
+use crate::vfs::Vfs;
+
+/// Id of the base game wherever mods are identified
+pub const GAME_ID: &str = "Stellaris";
+
+/// `descriptor.mod` inside a mod folder, or a launcher file in the user's `mod` folder which
+/// also says where the content is
+#[derive(JominiDeserialize, PartialEq, Debug, Clone, Serialize)]
+pub struct ModDescriptor {
+    pub name: String,
+
+    #[jomini(default)]
+    pub tags: Vec<String>,
+
+    pub version: Option<String>,
+    pub dependencies: Option<Vec<String>>,
+    pub picture: Option<String>,
+    pub supported_version: Option<String>,
+
+    /// Steam workshop id, mods that were never uploaded have none
+    pub remote_file_id: Option<String>,
+
+    /// Content folder of a launcher file, relative paths start at the user folder
+    pub path: Option<String>,
+
+    /// Zip file holding the content instead of a folder, relative paths start at the user folder
+    pub archive: Option<String>,
+
+    /// Game folders whose files are ignored once this mod loads
+    #[jomini(duplicated)]
+    pub replace_path: Vec<String>,
+}
+
+impl ModDescriptor {
+    /// Descriptor with only a name, every other field is left out
+    pub fn named(name: &str) -> ModDescriptor {
+        ModDescriptor {
+            name: name.to_string(),
+            tags: vec![],
+            version: None,
+            dependencies: None,
+            picture: None,
+            supported_version: None,
+            remote_file_id: None,
+            path: None,
+            archive: None,
+            replace_path: vec![],
+        }
+    }
+}
+
+/// A mod found on disk, `path` is the folder or the zip archive holding its content
+#[derive(Debug, Clone)]
+pub struct LocatedMod {
+    pub path: PathBuf,
+
+    /// Workshop id, else `local-`, the name of the launcher file or the content folder and a hash
+    /// of its path, which stay the same across runs unlike the display name
+    pub id: String,
+    pub descriptor: ModDescriptor,
+
+    /// Where `descriptor` was read from
+    pub file: PathBuf,
+}
+
+fn read_descriptor_file<V: Vfs>(vfs: &V, file: &Path) -> Result<ModDescriptor, ParseError> {
+    let data = vfs.read(&vfs.resolve(file)).map_err(|e| ParseError::io(file, e))?;
+    jomini::text::de::from_utf8_slice(&data).map_err(|e| ParseError::jomini(file, &data, e))
+}
+
+/// Reads the mod at `entry` in `vfs`: a mod folder with a `descriptor.mod`, a zip archive with one, or a
+/// launcher `.mod` file pointing at either. The launcher file is what the game reads, so its fields win.
+/// Archives are mounted in `vfs`, see [Vfs::mount].
+pub fn locate_mod<V: Vfs>(vfs: &V, entry: &Path) -> Result<LocatedMod, ParseError> {
+    //trace_time!("Parsing descriptor for {:?}", entry);
+    if vfs.is_dir(entry) {
+        let file = entry.join("descriptor.mod");
+        // Older workshop mods are a zip alone in their folder
+        if !vfs.is_file(&vfs.resolve(&file)) {
+            if let Some(zip) = vfs.list_files(entry, "zip", false).ok().filter(|x| x.len() == 1).and_then(|mut x| x.pop()) {
+                return locate_archive(vfs, &zip, entry);
+            }
+        }
+        let descriptor = read_descriptor_file(vfs, &file)?;
+        let id = local_id(&descriptor, entry);
+        return Ok(LocatedMod { path: entry.to_path_buf(), id, descriptor, file });
+    }
+
+    if entry.extension().is_some_and(|x| x.eq_ignore_ascii_case("zip")) {
+        return locate_archive(vfs, entry, entry);
+    }
+
+    let descriptor = read_descriptor_file(vfs, entry)?;
+    // Launcher files live in `<user folder>/mod`
+    let user_folder = entry.parent().and_then(|x| x.parent()).unwrap_or(Path::new("."));
+    let path = match (&descriptor.path, &descriptor.archive) {
+        (Some(path), _) => user_folder.join(path),
+        (None, Some(zip)) => {
+            let path = user_folder.join(zip);
+            vfs.mount(&path).map_err(|e| ParseError::io(&path, e))?;
+            path
+        }
+        (None, None) => return Err(ParseError::invalid(entry, format!("Mod {} has neither path nor archive", descriptor.name))),
+    };
+    if !vfs.is_dir(&path) {
+        return Err(ParseError::io(&path, io::Error::new(io::ErrorKind::NotFound, "mod folder not found")));
+    }
+
+    let id = local_id(&descriptor, entry);
+    Ok(LocatedMod { path, id, descriptor, file: entry.to_path_buf() })
+}
+
+/// Mod packed in the zip at `path` with its `descriptor.mod` inside, `source` is what the mod was found as
+fn locate_archive<V: Vfs>(vfs: &V, path: &Path, source: &Path) -> Result<LocatedMod, ParseError> {
+    vfs.mount(path).map_err(|e| ParseError::io(path, e))?;
+    let file = path.join("descriptor.mod");
+    let descriptor = read_descriptor_file(vfs, &file)?;
+    let id = local_id(&descriptor, source);
+    Ok(LocatedMod { path: path.to_path_buf(), id, descriptor, file })
+}
+
+fn local_id(descriptor: &ModDescriptor, source: &Path) -> String {
+    match &descriptor.remote_file_id {
+        Some(x) => x.clone(),
+        // The name alone is not unique, every mod folder has a `descriptor.mod`
+        None => format!(
+            "local-{}-{:08x}",
+            source.file_stem().map_or_else(|| descriptor.name.clone(), |x| x.to_string_lossy().to_string()),
+            xxh3_64(source.to_string_lossy().as_bytes()) as u32,
+        ),
+    }
+}
+
+/// Folders whose files are ignored because a mod loading later lists them in `replace_path`.
+/// Only files directly inside a folder are replaced, not its subfolders.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct ReplacedPaths(BTreeSet<String>);
+
+impl ReplacedPaths {
+    /// Folders replaced by any of `descriptors`
+    pub fn of<'a>(descriptors: impl IntoIterator<Item = &'a ModDescriptor>) -> ReplacedPaths {
+        ReplacedPaths(descriptors.into_iter().flat_map(|x| x.replace_path.iter()).map(|x| normalize(x)).collect())
+    }
+
+    /// Whether the folder at `dir`, relative to a mod root, is replaced
+    pub fn contains<P: AsRef<Path>>(&self, dir: P) -> bool {
+        !self.0.is_empty() && self.0.contains(&normalize(&dir.as_ref().to_string_lossy()))
+    }
+
+    /// Whether `file` of the mod in `root` is in a replaced folder
+    pub fn covers(&self, root: &Path, file: &Path) -> bool {
+        file.strip_prefix(root).ok().and_then(|x| x.parent()).is_some_and(|x| self.contains(x))
+    }
+}
+
+/// `common\technology\`, `Common/Technology` and `common/technology` name the same folder
+fn normalize(path: &str) -> String {
+    path.replace('\\', "/").trim_matches('/').to_lowercase()
+}
+
+/// Launcher files in the `mod` folder of the user folder, e.g. `Documents/Paradox Interactive/Stellaris`
+pub fn launcher_files<V: Vfs>(vfs: &V, user_folder: &Path) -> io::Result<Vec<PathBuf>> {
+    vfs.list_files(&user_folder.join("mod"), "mod", false)
+}
+
+/// The base game in `path` as a mod, its version comes from the launcher settings
+pub fn read_game_descriptor<V: Vfs>(vfs: &V, path: &Path) -> io::Result<ModDescriptor> {
+    let version = serde_json::from_slice::<serde_json::Value>(&vfs.read(&path.join("launcher-settings.json"))?)
+        .ok()
+        .and_then(|x| x.get("rawVersion").and_then(|y| y.as_str()).map(|y| y.to_string()));
+
+    Ok(ModDescriptor { version, ..ModDescriptor::named(GAME_ID) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn descriptor(text: &str) -> ModDescriptor {
+        jomini::text::de::from_utf8_slice(text.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn local_ids_tell_mods_with_the_same_file_name_apart() {
+        let local = descriptor("name=\"Mod\"");
+        // Folders of the same name in different places
+        let a = local_id(&local, Path::new("mods/x/a/descriptor.mod"));
+        assert_ne!(a, local_id(&local, Path::new("mods/y/a/descriptor.mod")));
+        assert_eq!(a, local_id(&local, Path::new("mods/x/a/descriptor.mod")));
+
+        // Launcher files of the same name in different user folders
+        assert_ne!(local_id(&local, Path::new("x/mod/a.mod")), local_id(&local, Path::new("y/mod/a.mod")));
+
+        assert_eq!(local_id(&descriptor("name=\"Mod\" remote_file_id=\"123\""), Path::new("mods/a/descriptor.mod")), "123");
+    }
+}
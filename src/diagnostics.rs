@@ -0,0 +1,421 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::io;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use ariadne::{Cache, Config, Label, Report, ReportKind, Source};
+use log::info;
+use serde::{Deserialize, Serialize};
+use crate::localisation::{tokenize_line, LocalisationLine};
+use crate::markup::Markup;
+use crate::vfs::Vfs;
+
+/// Reports printed to the terminal, the report files always get all of them
+const TERMINAL_LIMIT: usize = 50;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+/// A problem found while loading one mod, the offending file is skipped and loading goes on
+#[derive(Debug, Clone, Serialize)]
+pub struct Diagnostic {
+    pub severity: Severity,
+
+    #[serde(rename = "mod")]
+    pub mod_name: Option<String>,
+    pub file: PathBuf,
+
+    /// 1-based line of the problem if known
+    pub line: Option<usize>,
+
+    /// Byte offset in the file if known
+    pub offset: Option<usize>,
+
+    /// Length in bytes of the offending text starting at `offset`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub length: Option<usize>,
+    pub message: String,
+
+    /// Short note attached to the span, e.g. what is wrong with the highlighted text
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+
+    /// Other places the problem involves, e.g. the first definition of a duplicate key
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub related: Vec<Related>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Related {
+    pub file: PathBuf,
+    pub line: usize,
+    pub offset: usize,
+    pub length: usize,
+    pub message: String,
+}
+
+impl Diagnostic {
+    /// A problem at `span` (byte offsets) of `data`, the content of `file`
+    pub fn warning(mod_name: &str, file: &Path, data: &[u8], span: Range<usize>, message: String) -> Diagnostic {
+        Diagnostic {
+            severity: Severity::Warning,
+            mod_name: Some(mod_name.to_string()),
+            file: file.to_path_buf(),
+            line: Some(line_of(data, span.start)),
+            offset: Some(span.start),
+            length: Some(span.len()),
+            message,
+            label: None,
+            related: vec![],
+        }
+    }
+
+    pub fn with_label(mut self, label: String) -> Diagnostic {
+        self.label = Some(label);
+        self
+    }
+
+    pub fn with_related(mut self, related: Related) -> Diagnostic {
+        self.related.push(related);
+        self
+    }
+
+    /// Builds the annotated report, offsets are converted to the character offsets ariadne expects
+    fn report<V: Vfs>(&self, sources: &mut Sources<V>, color: bool) -> Report<(PathBuf, Range<usize>)> {
+        let kind = match self.severity {
+            Severity::Error => ReportKind::Error,
+            Severity::Warning => ReportKind::Warning,
+        };
+        let message = match &self.mod_name {
+            Some(mod_name) => format!("[{}] {}", mod_name, self.message),
+            None => self.message.clone(),
+        };
+
+        let span = sources.span(&self.file, self.offset, self.length, self.line);
+        let mut builder = Report::build(kind, self.file.clone(), span.as_ref().map_or(0, |x| x.start))
+            .with_config(Config::default().with_color(color))
+            .with_message(message);
+
+        match span {
+            Some(span) => {
+                builder.add_label(
+                    Label::new((self.file.clone(), span)).with_message(self.label.as_deref().unwrap_or(&self.message)),
+                );
+                // How the game shows a localisation value, colours only make it to a terminal
+                if let Some(value) = self.line.and_then(|line| sources.localisation_value(&self.file, line)) {
+                    let markup = Markup::parse(&value);
+                    builder.set_note(format!("shown as: {}", if color { markup.to_ansi() } else { markup.to_plain() }));
+                }
+            }
+            // Nothing to point at, e.g. the file could not be read
+            None => builder.set_note(self.file.display()),
+        }
+
+        for x in &self.related {
+            if let Some(span) = sources.span(&x.file, Some(x.offset), Some(x.length), Some(x.line)) {
+                builder.add_label(Label::new((x.file.clone(), span)).with_message(&x.message));
+            }
+        }
+
+        builder.finish()
+    }
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if let Some(mod_name) = &self.mod_name {
+            write!(f, "[{}] ", mod_name)?;
+        }
+        write!(f, "{}", self.file.display())?;
+        if let Some(line) = self.line {
+            write!(f, ":{}", line)?;
+        }
+        write!(f, ": {}", self.message)
+    }
+}
+
+#[derive(Debug)]
+pub enum ParseError {
+    Io {
+        file: PathBuf,
+        source: io::Error,
+    },
+    Syntax {
+        file: PathBuf,
+        line: Option<usize>,
+        offset: Option<usize>,
+        message: String,
+    },
+}
+
+impl ParseError {
+    pub fn io(file: &Path, source: io::Error) -> ParseError {
+        ParseError::Io {
+            file: file.to_path_buf(),
+            source,
+        }
+    }
+
+    /// Wraps a jomini error, the byte offset is turned into a line of `data`
+    pub fn jomini(file: &Path, data: &[u8], error: jomini::Error) -> ParseError {
+        let offset = error.offset();
+        ParseError::Syntax {
+            file: file.to_path_buf(),
+            line: offset.map(|x| line_of(data, x)),
+            offset,
+            message: error.to_string(),
+        }
+    }
+
+    /// A file that parsed but cannot be used, e.g. a descriptor missing a required field
+    pub fn invalid(file: &Path, message: String) -> ParseError {
+        ParseError::Syntax {
+            file: file.to_path_buf(),
+            line: None,
+            offset: None,
+            message,
+        }
+    }
+
+    pub fn into_diagnostic(self, mod_name: Option<&str>) -> Diagnostic {
+        let (file, line, offset, message) = match self {
+            ParseError::Io { file, source } => (file, None, None, format!("I/O failed, {}", source)),
+            ParseError::Syntax { file, line, offset, message } => (file, line, offset, message),
+        };
+
+        Diagnostic {
+            severity: Severity::Error,
+            mod_name: mod_name.map(|x| x.to_string()),
+            file,
+            line,
+            offset,
+            length: None,
+            message,
+            label: None,
+            related: vec![],
+        }
+    }
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParseError::Io { file, source } => write!(f, "{}: I/O failed, {}", file.display(), source),
+            ParseError::Syntax { file, line: Some(line), message, .. } => write!(f, "{}:{}: {}", file.display(), line, message),
+            ParseError::Syntax { file, line: None, message, .. } => write!(f, "{}: {}", file.display(), message),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ParseError::Io { source, .. } => Some(source),
+            ParseError::Syntax { .. } => None,
+        }
+    }
+}
+
+/// 1-based line containing byte `offset`
+pub fn line_of(data: &[u8], offset: usize) -> usize {
+    data[..offset.min(data.len())].iter().filter(|x| **x == b'\n').count() + 1
+}
+
+/// Diagnostics of a whole run, shared by the parallel readers
+#[derive(Debug, Default)]
+pub struct Diagnostics(Mutex<Vec<Diagnostic>>);
+
+impl Diagnostics {
+    pub fn push(&self, diagnostic: Diagnostic) {
+        self.0.lock().unwrap().push(diagnostic);
+    }
+
+    /// Scopes reported errors to one mod
+    pub fn reporter<'a>(&'a self, mod_name: &'a str) -> Reporter<'a> {
+        Reporter {
+            diagnostics: self,
+            mod_name,
+        }
+    }
+
+    pub fn error_count(&self) -> usize {
+        self.0.lock().unwrap().iter().filter(|x| x.severity == Severity::Error).count()
+    }
+
+    pub fn warning_count(&self) -> usize {
+        self.0.lock().unwrap().iter().filter(|x| x.severity == Severity::Warning).count()
+    }
+
+    pub fn to_vec(&self) -> Vec<Diagnostic> {
+        self.0.lock().unwrap().clone()
+    }
+
+    /// Prints every diagnostic as an annotated source excerpt to stderr and writes them
+    /// to `diagnostics.txt` and `diagnostics.json` in `dir`, the excerpts are read from `vfs`
+    pub fn emit<V: Vfs>(&self, vfs: &V, dir: &Path) -> io::Result<()> {
+        let mut diagnostics = self.to_vec();
+        diagnostics.sort_by(|a, b| (&a.mod_name, &a.file, a.offset).cmp(&(&b.mod_name, &b.file, b.offset)));
+
+        let mut sources = Sources { vfs, files: HashMap::new() };
+        let mut text = vec![];
+        let stderr = io::stderr();
+        for (i, x) in diagnostics.iter().enumerate() {
+            if i < TERMINAL_LIMIT {
+                x.report(&mut sources, true).write(&mut sources, stderr.lock())?;
+            }
+            x.report(&mut sources, false).write(&mut sources, &mut text)?;
+        }
+
+        if diagnostics.len() > TERMINAL_LIMIT {
+            eprintln!("... and {} more, see {}", diagnostics.len() - TERMINAL_LIMIT, dir.join("diagnostics.txt").display());
+        }
+
+        fs::write(dir.join("diagnostics.txt"), text)?;
+        fs::write(dir.join("diagnostics.json"), serde_json::to_string_pretty(&diagnostics)?)?;
+
+        let errors = diagnostics.iter().filter(|x| x.severity == Severity::Error).count();
+        info!("{} errors and {} warnings, written to {}", errors, diagnostics.len() - errors, dir.join("diagnostics.txt").display());
+        Ok(())
+    }
+}
+
+/// A file referenced by reports, `text` is `None` when every byte was taken as one character
+struct SourceFile {
+    text: Option<String>,
+    source: Source,
+}
+
+impl SourceFile {
+    fn char_offset(&self, offset: usize) -> usize {
+        match &self.text {
+            Some(text) => text.char_indices().take_while(|(i, _)| *i < offset).count(),
+            None => offset,
+        }
+    }
+}
+
+/// Source files referenced by reports, read once per [`Diagnostics::emit`]
+struct Sources<'a, V> {
+    vfs: &'a V,
+    files: HashMap<PathBuf, Option<SourceFile>>,
+}
+
+impl<V: Vfs> Sources<'_, V> {
+    fn load(&mut self, file: &Path) -> Option<&SourceFile> {
+        self.files
+            .entry(file.to_path_buf())
+            .or_insert_with(|| {
+                let data = self.vfs.read(file).ok()?;
+                Some(match String::from_utf8(data) {
+                    Ok(text) => SourceFile {
+                        source: Source::from(&text),
+                        text: Some(text),
+                    },
+                    // Script files may be windows-1252, keep one character per byte so offsets still line up
+                    Err(e) => SourceFile {
+                        source: Source::from(e.into_bytes().into_iter().map(|x| x as char).collect::<String>()),
+                        text: None,
+                    },
+                })
+            })
+            .as_ref()
+    }
+
+    /// The value on 1-based `line` of a localisation file, `None` for other lines and files
+    fn localisation_value(&mut self, file: &Path, line: usize) -> Option<String> {
+        if !file.extension().is_some_and(|x| x.eq_ignore_ascii_case("yml")) {
+            return None;
+        }
+        let text = self.load(file)?.text.as_deref()?;
+        match tokenize_line(text.lines().nth(line.checked_sub(1)?)?) {
+            Ok(Some(LocalisationLine::Entry { value, .. })) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// Character range of a byte span, or of the whole line if only the line is known
+    fn span(&mut self, file: &Path, offset: Option<usize>, length: Option<usize>, line: Option<usize>) -> Option<Range<usize>> {
+        let file = self.load(file)?;
+        match (offset, line) {
+            (Some(offset), _) => {
+                let start = file.char_offset(offset);
+                let end = file.char_offset(offset + length.unwrap_or(1).max(1));
+                Some(start..end.max(start + 1))
+            }
+            (None, Some(line)) => file.source.line(line.checked_sub(1)?).map(|x| x.offset()..x.offset() + x.len()),
+            (None, None) => None,
+        }
+    }
+}
+
+impl<V: Vfs> Cache<PathBuf> for Sources<'_, V> {
+    fn fetch(&mut self, id: &PathBuf) -> Result<&Source, Box<dyn std::fmt::Debug + '_>> {
+        match self.load(id) {
+            Some(file) => Ok(&file.source),
+            None => Err(Box::new(format!("Cannot read {}", id.display()))),
+        }
+    }
+
+    fn display<'a>(&self, id: &'a PathBuf) -> Option<Box<dyn Display + 'a>> {
+        Some(Box::new(id.display()))
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Reporter<'a> {
+    diagnostics: &'a Diagnostics,
+    mod_name: &'a str,
+}
+
+impl<'a> Reporter<'a> {
+    pub fn report(&self, error: ParseError) {
+        self.diagnostics.push(error.into_diagnostic(Some(self.mod_name)));
+    }
+
+    /// Warns about `file` as a whole, for problems without a place in its content
+    pub fn warn(&self, file: &Path, message: String) {
+        self.diagnostics.push(Diagnostic {
+            severity: Severity::Warning,
+            mod_name: Some(self.mod_name.to_string()),
+            file: file.to_path_buf(),
+            line: None,
+            offset: None,
+            length: None,
+            message,
+            label: None,
+            related: vec![],
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vfs::Memory;
+
+    fn render(diagnostic: &Diagnostic, vfs: &Memory, color: bool) -> String {
+        let mut sources = Sources { vfs, files: HashMap::new() };
+        let mut out = vec![];
+        diagnostic.report(&mut sources, color).write(&mut sources, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn reports_on_localisation_values_show_the_rendered_text() {
+        let data = "l_english:\n key:0 \"§RRed§! £energy£ text\"\n";
+        let vfs = Memory::default().with_file("x_l_english.yml", data).with_file("x.txt", "key = { }");
+        let warning = Diagnostic::warning("Mod", Path::new("x_l_english.yml"), data.as_bytes(), 12..15, "Check".to_string());
+
+        assert!(render(&warning, &vfs, false).contains("shown as: Red  text"));
+        assert!(render(&warning, &vfs, true).contains("\x1b[38;2;226;59;59mRed\x1b[0m  text"));
+
+        let script = Diagnostic::warning("Mod", Path::new("x.txt"), b"key = { }", 0..3, "Check".to_string());
+        assert!(!render(&script, &vfs, false).contains("shown as"));
+    }
+}
@@ -0,0 +1,501 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::rc::Rc;
+use serde::Serialize;
+use crate::tech_tree::{Node, TechnologyTree};
+
+pub const NODE_WIDTH: f64 = 220.0;
+pub const NODE_HEIGHT: f64 = 48.0;
+pub const LAYER_SPACING: f64 = 320.0;
+pub const NODE_SPACING: f64 = 72.0;
+pub const LANE_SPACING: f64 = 120.0;
+
+/// How technologies are assigned to columns
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LayeringStrategy {
+    /// Column is the length of the longest prerequisite chain, every edge points forward
+    LongestPath,
+
+    /// Column is the technology tier, prerequisites of the same tier end up in the same column
+    Tier,
+}
+
+#[derive(Debug, Clone)]
+pub struct LayoutOptions {
+    pub layering: LayeringStrategy,
+
+    /// Split the rows into one lane per research area
+    pub area_lanes: bool,
+
+    /// Number of barycenter sweeps used for crossing minimisation
+    pub sweeps: usize,
+}
+
+impl Default for LayoutOptions {
+    fn default() -> Self {
+        LayoutOptions {
+            layering: LayeringStrategy::LongestPath,
+            area_lanes: true,
+            sweeps: 8,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct NodePosition {
+    pub layer: usize,
+    pub lane: usize,
+    pub x: f64,
+    pub y: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct EdgeRoute {
+    pub source: String,
+    pub target: String,
+
+    /// Polyline from the right side of the source to the left side of the target
+    pub points: Vec<(f64, f64)>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Lane {
+    pub area: String,
+    pub y: f64,
+    pub height: f64,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct Layout {
+    pub width: f64,
+    pub height: f64,
+    pub crossings: usize,
+
+    pub lanes: Vec<Lane>,
+    pub nodes: BTreeMap<String, NodePosition>,
+    pub edges: Vec<EdgeRoute>,
+}
+
+/// A vertex of the layered graph, either a technology or a dummy
+/// standing in for an edge spanning several layers
+struct Vertex {
+    node: Option<usize>,
+    layer: usize,
+    lane: usize,
+    up: Vec<usize>,
+    down: Vec<usize>,
+}
+
+impl Layout {
+    /// Sugiyama-style layered layout: layer assignment, dummy insertion for long edges,
+    /// barycenter crossing minimisation inside each area lane and coordinate assignment.
+    pub fn compute(tree: &TechnologyTree, options: &LayoutOptions) -> Layout {
+        let nodes: Vec<&Rc<Node>> = {
+            let mut nodes: Vec<&Rc<Node>> = tree.node_map.values().collect();
+            nodes.sort_by(|a, b| a.name.cmp(&b.name));
+            nodes
+        };
+        let index: HashMap<&str, usize> = nodes.iter().enumerate().map(|(i, x)| (x.name.as_str(), i)).collect();
+
+        let edges: BTreeSet<(usize, usize)> = nodes
+            .iter()
+            .enumerate()
+            .flat_map(|(i, node)| {
+                node.prev
+                    .borrow()
+                    .iter()
+                    .filter_map(|prev| index.get(prev.name.as_str()).copied())
+                    .filter(|prev| *prev != i)
+                    .map(|prev| (prev, i))
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+
+        let layers: Vec<usize> = match options.layering {
+            LayeringStrategy::LongestPath => {
+                let mut layers = HashMap::new();
+                nodes.iter().map(|x| longest_path(x, &mut layers)).collect()
+            }
+            LayeringStrategy::Tier => nodes
+                .iter()
+                .map(|x| x.data.as_ref().and_then(|x| x.tier.as_ref()).and_then(|x| x.parse().ok()).unwrap_or_default())
+                .collect(),
+        };
+
+        let lane_names: Vec<&str> = if options.area_lanes {
+            let mut names: Vec<&str> = nodes.iter().map(|x| area_of(x)).collect::<BTreeSet<_>>().into_iter().collect();
+            names.sort_by_key(|x| (lane_rank(x), *x));
+            names
+        } else {
+            vec![""]
+        };
+        let lane_of = |node: &Node| if options.area_lanes {
+            lane_names.iter().position(|x| *x == area_of(node)).unwrap_or_default()
+        } else {
+            0
+        };
+
+        let mut vertices: Vec<Vertex> = nodes
+            .iter()
+            .enumerate()
+            .map(|(i, node)| Vertex {
+                node: Some(i),
+                layer: layers[i],
+                lane: lane_of(node),
+                up: vec![],
+                down: vec![],
+            })
+            .collect();
+
+        // Chains of vertices each edge is routed through, edges that do not point forward are drawn directly
+        let mut chains: Vec<(usize, usize, Vec<usize>)> = vec![];
+        for (source, target) in edges.iter().copied() {
+            let mut chain = vec![source];
+            if layers[target] > layers[source] {
+                for layer in layers[source] + 1..layers[target] {
+                    vertices.push(Vertex {
+                        node: None,
+                        layer,
+                        lane: vertices[target].lane,
+                        up: vec![],
+                        down: vec![],
+                    });
+                    chain.push(vertices.len() - 1);
+                }
+                chain.push(target);
+                chain.windows(2).for_each(|w| {
+                    vertices[w[0]].down.push(w[1]);
+                    vertices[w[1]].up.push(w[0]);
+                });
+            } else {
+                chain.push(target);
+            }
+            chains.push((source, target, chain));
+        }
+
+        let layer_count = vertices.iter().map(|x| x.layer + 1).max().unwrap_or_default();
+        let mut order: Vec<Vec<usize>> = vec![vec![]; layer_count];
+        vertices.iter().enumerate().for_each(|(i, x)| order[x.layer].push(i));
+        order.iter_mut().for_each(|layer| layer.sort_by_key(|x| vertices[*x].lane));
+
+        let mut best = order.clone();
+        let mut best_crossings = count_crossings(&vertices, &order);
+        for sweep in 0..options.sweeps {
+            if best_crossings == 0 {
+                break;
+            }
+
+            if sweep % 2 == 0 {
+                for layer in 1..layer_count {
+                    reorder(&vertices, &mut order, layer, layer - 1, |x| &x.up);
+                }
+            } else {
+                for layer in (0..layer_count.saturating_sub(1)).rev() {
+                    reorder(&vertices, &mut order, layer, layer + 1, |x| &x.down);
+                }
+            }
+
+            let crossings = count_crossings(&vertices, &order);
+            if crossings < best_crossings {
+                best_crossings = crossings;
+                best = order.clone();
+            }
+        }
+        let order = best;
+
+        // Lanes are as tall as their most populated layer
+        let mut lanes: Vec<Lane> = vec![];
+        let mut slot: Vec<usize> = vec![0; vertices.len()];
+        let mut y = 0.0;
+        for (lane, area) in lane_names.iter().enumerate() {
+            let mut rows = 0;
+            for layer in &order {
+                let mut row = 0;
+                for vertex in layer.iter().filter(|x| vertices[**x].lane == lane) {
+                    slot[*vertex] = row;
+                    row += 1;
+                }
+                rows = rows.max(row);
+            }
+            lanes.push(Lane {
+                area: area.to_string(),
+                y,
+                height: rows as f64 * NODE_SPACING,
+            });
+            y += rows as f64 * NODE_SPACING + LANE_SPACING;
+        }
+
+        let position = |vertex: usize| -> (f64, f64) {
+            let v = &vertices[vertex];
+            (v.layer as f64 * LAYER_SPACING, lanes[v.lane].y + slot[vertex] as f64 * NODE_SPACING)
+        };
+
+        let mut layout = Layout {
+            crossings: best_crossings,
+            ..Default::default()
+        };
+
+        for (i, vertex) in vertices.iter().enumerate() {
+            if let Some(node) = vertex.node {
+                let (x, y) = position(i);
+                layout.width = layout.width.max(x + NODE_WIDTH);
+                layout.height = layout.height.max(y + NODE_HEIGHT);
+                layout.nodes.insert(nodes[node].name.to_string(), NodePosition {
+                    layer: vertex.layer,
+                    lane: vertex.lane,
+                    x,
+                    y,
+                });
+            }
+        }
+
+        layout.edges = chains
+            .into_iter()
+            .map(|(source, target, chain)| {
+                let mut points = vec![];
+                let (x, y) = position(source);
+                points.push((x + NODE_WIDTH, y + NODE_HEIGHT / 2.0));
+                for dummy in &chain[1..chain.len() - 1] {
+                    let (x, y) = position(*dummy);
+                    points.push((x, y + NODE_HEIGHT / 2.0));
+                    points.push((x + NODE_WIDTH, y + NODE_HEIGHT / 2.0));
+                }
+                let (x, y) = position(target);
+                points.push((x, y + NODE_HEIGHT / 2.0));
+
+                EdgeRoute {
+                    source: nodes[source].name.to_string(),
+                    target: nodes[target].name.to_string(),
+                    points,
+                }
+            })
+            .collect();
+
+        layout.lanes = lanes;
+        layout
+    }
+}
+
+fn area_of(node: &Node) -> &str {
+    node.data.as_ref().map_or("", |x| x.area.as_ref())
+}
+
+fn lane_rank(area: &str) -> usize {
+    match area {
+        "physics" => 0,
+        "society" => 1,
+        "engineering" => 2,
+        "" => 4,
+        _ => 3,
+    }
+}
+
+/// Sorts `layer` by the mean position of each vertex's neighbours in `fixed`,
+/// vertices without neighbours keep their position and lanes are never mixed.
+fn reorder<F: Fn(&Vertex) -> &Vec<usize>>(vertices: &[Vertex], order: &mut [Vec<usize>], layer: usize, fixed: usize, neighbours: F) {
+    let positions: HashMap<usize, usize> = order[fixed].iter().enumerate().map(|(i, x)| (*x, i)).collect();
+
+    let mut keyed: Vec<(usize, f64, usize)> = order[layer]
+        .iter()
+        .enumerate()
+        .map(|(i, x)| {
+            let adjacent = neighbours(&vertices[*x]);
+            let barycenter = if adjacent.is_empty() {
+                i as f64
+            } else {
+                adjacent.iter().map(|y| positions[y] as f64).sum::<f64>() / adjacent.len() as f64
+            };
+            (vertices[*x].lane, barycenter, *x)
+        })
+        .collect();
+
+    keyed.sort_by(|a, b| a.0.cmp(&b.0).then(a.1.total_cmp(&b.1)));
+    order[layer] = keyed.into_iter().map(|(_, _, x)| x).collect();
+}
+
+/// Counts crossings between every pair of adjacent layers by counting inversions of
+/// the segment targets with a Fenwick tree.
+fn count_crossings(vertices: &[Vertex], order: &[Vec<usize>]) -> usize {
+    order
+        .windows(2)
+        .map(|w| {
+            let positions: HashMap<usize, usize> = w[1].iter().enumerate().map(|(i, x)| (*x, i)).collect();
+            let mut segments: Vec<(usize, usize)> = w[0]
+                .iter()
+                .enumerate()
+                .flat_map(|(i, x)| vertices[*x].down.iter().map(move |y| (i, *y)))
+                .filter_map(|(i, y)| positions.get(&y).map(|j| (i, *j)))
+                .collect();
+            segments.sort_unstable();
+
+            let mut tree = vec![0usize; w[1].len() + 1];
+            let mut crossings = 0;
+            for (inserted, (_, target)) in segments.into_iter().enumerate() {
+                let mut not_greater = 0;
+                let mut i = target + 1;
+                while i > 0 {
+                    not_greater += tree[i];
+                    i &= i - 1;
+                }
+                crossings += inserted - not_greater;
+
+                let mut i = target + 1;
+                while i < tree.len() {
+                    tree[i] += 1;
+                    i += i & i.wrapping_neg();
+                }
+            }
+            crossings
+        })
+        .sum()
+}
+
+/// Memoized depth of the longest prerequisite chain leading to `node`. A prerequisite leading back
+/// into the chain being followed closes a cycle and is left out, so the cycle starts at layer 0.
+/// The chain is followed with an explicit stack, prerequisite chains can be deeper than the call stack.
+fn longest_path(node: &Rc<Node>, layers: &mut HashMap<String, usize>) -> usize {
+    if let Some(layer) = layers.get(&node.name) {
+        return *layer;
+    }
+
+    // Nodes of the chain being followed, with the index of their next prerequisite to visit
+    let mut stack: Vec<(Rc<Node>, usize)> = vec![(node.clone(), 0)];
+    let mut on_stack: HashSet<String> = HashSet::from([node.name.clone()]);
+    while let Some((current, next)) = stack.last_mut() {
+        let prerequisite = current.prev.borrow().get(*next).cloned();
+        *next += 1;
+        match prerequisite {
+            Some(x) => {
+                if !layers.contains_key(&x.name) && on_stack.insert(x.name.clone()) {
+                    stack.push((x, 0));
+                }
+            }
+            None => {
+                // Every prerequisite without a layer is on the stack, an edge back into the chain
+                let layer = current.prev
+                    .borrow()
+                    .iter()
+                    .filter_map(|x| layers.get(&x.name).map(|layer| layer + 1))
+                    .max()
+                    .unwrap_or_default();
+                layers.insert(current.name.clone(), layer);
+                on_stack.remove(&current.name);
+                stack.pop();
+            }
+        }
+    }
+    layers[&node.name]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::{ResearchArea, Technology};
+
+    /// Tree of `(id, tier, area, prerequisites)`
+    fn tree(techs: &[(&str, &str, &str, &[&str])]) -> TechnologyTree {
+        let techs: Vec<(&str, Rc<Technology>)> = techs
+            .iter()
+            .map(|(id, tier, area, prerequisites)| {
+                (*id, Rc::new(Technology {
+                    id: id.to_string(),
+                    tier: Some(tier.to_string()),
+                    area: area.parse::<ResearchArea>().unwrap(),
+                    prerequisites: prerequisites.iter().map(|x| x.to_string()).collect(),
+                    ..Default::default()
+                }))
+            })
+            .collect();
+
+        let mut tree = TechnologyTree::default();
+        tree.insert_map(&techs.into_iter().collect());
+        tree
+    }
+
+    fn layers(layout: &Layout) -> BTreeMap<&str, usize> {
+        layout.nodes.iter().map(|(k, v)| (k.as_str(), v.layer)).collect()
+    }
+
+    fn vertex(layer: usize, down: Vec<usize>) -> Vertex {
+        Vertex { node: None, layer, lane: 0, up: vec![], down }
+    }
+
+    #[test]
+    fn longest_path_puts_every_tech_after_its_longest_chain() {
+        let tree = tree(&[
+            ("a", "0", "physics", &[]),
+            ("b", "1", "physics", &["a"]),
+            ("c", "1", "physics", &["b"]),
+            ("d", "1", "physics", &["a", "c"]),
+        ]);
+        let layout = Layout::compute(&tree, &LayoutOptions::default());
+
+        assert_eq!(layers(&layout), BTreeMap::from([("a", 0), ("b", 1), ("c", 2), ("d", 3)]));
+        // a -> d spans two layers and is routed through two dummies
+        let edge = layout.edges.iter().find(|x| x.source == "a" && x.target == "d").unwrap();
+        assert_eq!(edge.points.len(), 6);
+    }
+
+    #[test]
+    fn longest_path_breaks_cycles() {
+        let tree = tree(&[("a", "0", "physics", &["b"]), ("b", "0", "physics", &["a"])]);
+        let layout = Layout::compute(&tree, &LayoutOptions::default());
+
+        // a is laid out first, the edge from b back to a is left out
+        assert_eq!(layers(&layout), BTreeMap::from([("a", 1), ("b", 0)]));
+    }
+
+    #[test]
+    fn longest_path_follows_chains_deeper_than_the_call_stack() {
+        let ids: Vec<String> = (0..100_000).map(|i| format!("t{}", i)).collect();
+        let ids: Vec<&str> = ids.iter().map(|x| x.as_str()).collect();
+        // Every tech needs the one before it
+        let techs: Vec<(&str, &str, &str, &[&str])> = ids
+            .iter()
+            .enumerate()
+            .map(|(i, id)| (*id, "0", "physics", if i == 0 { &[][..] } else { &ids[i - 1..i] }))
+            .collect();
+        let tree = tree(&techs);
+
+        assert_eq!(longest_path(&tree.node_map["t99999"], &mut HashMap::new()), 99_999);
+    }
+
+    #[test]
+    fn tier_strategy_uses_the_tier_as_column() {
+        let tree = tree(&[
+            ("a", "0", "physics", &[]),
+            ("b", "2", "physics", &["a"]),
+            ("c", "2", "society", &["b"]),
+            ("d", "nope", "engineering", &[]),
+        ]);
+        let options = LayoutOptions { layering: LayeringStrategy::Tier, ..Default::default() };
+        let layout = Layout::compute(&tree, &options);
+
+        assert_eq!(layers(&layout), BTreeMap::from([("a", 0), ("b", 2), ("c", 2), ("d", 0)]));
+        assert_eq!(layout.lanes.iter().map(|x| x.area.as_str()).collect::<Vec<_>>(), ["physics", "society", "engineering"]);
+    }
+
+    #[test]
+    fn count_crossings_counts_inversions_between_layers() {
+        // 0 -> 3 and 1 -> 2 cross when the lower layer is ordered 2, 3
+        let vertices = vec![vertex(0, vec![3]), vertex(0, vec![2]), vertex(1, vec![]), vertex(1, vec![])];
+        assert_eq!(count_crossings(&vertices, &[vec![0, 1], vec![2, 3]]), 1);
+        assert_eq!(count_crossings(&vertices, &[vec![0, 1], vec![3, 2]]), 0);
+
+        // Every edge of a complete bipartite 3x3 graph against every other: 9 crossings
+        let mut vertices: Vec<Vertex> = (0..3).map(|_| vertex(0, vec![3, 4, 5])).collect();
+        vertices.extend((0..3).map(|_| vertex(1, vec![])));
+        assert_eq!(count_crossings(&vertices, &[vec![0, 1, 2], vec![3, 4, 5]]), 9);
+    }
+
+    #[test]
+    fn sweeps_remove_avoidable_crossings() {
+        let tree = tree(&[
+            ("a", "0", "physics", &[]),
+            ("b", "0", "physics", &[]),
+            ("y", "1", "physics", &["b"]),
+            ("z", "1", "physics", &["a"]),
+        ]);
+        let layout = Layout::compute(&tree, &LayoutOptions::default());
+        assert_eq!(layout.crossings, 0);
+    }
+}
@@ -0,0 +1,234 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+use anyhow::anyhow;
+use serde::Serialize;
+use crate::checks::{check_localisations, check_references, check_technologies, key_definitions, Definition, Symbols};
+use crate::data::{ResearchArea, TechnologyData};
+use crate::diagnostics::{Diagnostic, Diagnostics};
+use crate::localisation::{FallbackChain, Languages};
+use crate::descriptor::{read_game_descriptor, ReplacedPaths};
+use crate::load_order;
+use crate::vfs::Vfs;
+use crate::{locate_mods, parse_game_files, read_mods};
+
+pub const DEFAULT_REPORT: &str = "lint.json";
+
+/// Files besides localisation that may mention localisation keys
+const SCRIPT_EXTENSIONS: [&str; 5] = ["txt", "gui", "gfx", "asset", "sfx"];
+
+/// Keys the game derives from a scripted name, e.g. `building_x_desc` for `building_x`
+const KEY_SUFFIXES: [&str; 8] = ["_name", "_desc", ".name", ".desc", "_plural", "_adj", "_tooltip", "_effect"];
+
+/// Machine readable result of `lint`, written for CI
+#[derive(Serialize)]
+pub struct LintReport<'a> {
+    #[serde(rename = "mod")]
+    pub mod_path: &'a Path,
+    pub errors: usize,
+    pub warnings: usize,
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl<'a> LintReport<'a> {
+    pub fn new(mod_path: &'a Path, diagnostics: &Diagnostics) -> LintReport<'a> {
+        LintReport {
+            mod_path,
+            errors: diagnostics.error_count(),
+            warnings: diagnostics.warning_count(),
+            diagnostics: diagnostics.to_vec(),
+        }
+    }
+
+    pub fn write(&self, path: &Path) -> anyhow::Result<()> {
+        fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+}
+
+/// Whether `key` or the name it is derived from is in `used`
+fn is_used(used: &HashSet<&str>, key: &str) -> bool {
+    used.contains(key) || KEY_SUFFIXES.iter().any(|x| key.strip_suffix(x).is_some_and(|x| used.contains(x)))
+}
+
+/// Every identifier-like word in the script files of `root`, localisation folders excluded
+fn script_words<V: Vfs>(vfs: &V, root: &Path) -> HashSet<String> {
+    SCRIPT_EXTENSIONS
+        .iter()
+        .flat_map(|ext| vfs.list_files(root, ext, true).unwrap_or_default())
+        .filter(|x| !x.components().any(|x| x.as_os_str() == "localisation"))
+        .filter_map(|x| vfs.read(&x).ok())
+        .flat_map(|data| {
+            data.split(|x| !(x.is_ascii_alphanumeric() || b"_.-".contains(x)))
+                .filter(|x| !x.is_empty())
+                .map(|x| String::from_utf8_lossy(x).to_string())
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+/// Checks the mod in `mod_dir` against the game in `game_dir`, both read from `vfs`. On top of the load time checks it reports
+/// technologies without localisation, without `area` or replacing vanilla ones, localisation keys
+/// nothing refers to and a `supported_version` the game does not match. Problems found in the game
+/// files themselves are not reported.
+pub async fn lint<V: Vfs>(vfs: &V, mod_dir: &Path, game_dir: &Path, fallback: &FallbackChain, diagnostics: &Diagnostics) -> anyhow::Result<()> {
+    let located = locate_mods(vfs, &[mod_dir.to_string_lossy().to_string()], diagnostics);
+    // Dependencies are not checked, they are not part of a lint run
+    if let Some(version) = read_game_descriptor(vfs, game_dir).ok().and_then(|x| x.version) {
+        located.iter().for_each(|x| load_order::check_supported_version(vfs, x, &version, diagnostics));
+    }
+
+    let target = match read_mods(vfs, located, diagnostics)
+        .await
+        .map_err(|e| anyhow!("Reading {} failed, {}", mod_dir.display(), e))?
+        .pop()
+    {
+        Some(target) => target,
+        // The broken descriptor is already reported
+        None => return Ok(()),
+    };
+    let mod_name = target.descriptor.name.clone();
+    let vanilla = parse_game_files(vfs, game_dir, &ReplacedPaths::of([&target.descriptor]), &Diagnostics::default())
+        .map_err(|e| anyhow!("Reading game files in {} failed, {}", game_dir.display(), e))?;
+
+    // In load order, the mod overrides the game
+    let loaded = [vanilla, target];
+    let (vanilla, target) = (&loaded[0], &loaded[1]);
+    let symbols = Symbols::of(&loaded);
+
+    let technologies = check_technologies(target, &symbols, diagnostics);
+    let localisations = check_localisations(target, diagnostics);
+    let vanilla_technologies: HashMap<String, Definition> = check_technologies(vanilla, &symbols, &Diagnostics::default())
+        .into_iter()
+        .map(|x| (x.key.clone(), x))
+        .collect();
+
+    let mut keys: HashMap<&Languages, HashSet<&str>> = HashMap::new();
+    loaded.iter().flat_map(|x| x.localisations.iter()).for_each(|file| {
+        keys.entry(&file.language).or_default().extend(file.entries.keys().map(|x| x.as_str()));
+    });
+    let languages: BTreeSet<&Languages> = keys.keys().copied().filter(|x| **x != Languages::Default).collect();
+
+    let mut seen = HashSet::new();
+    for definition in technologies.iter().filter(|x| seen.insert(x.key.as_str())) {
+        let key = definition.key.as_str();
+
+        if let Some(original) = vanilla_technologies.get(key) {
+            diagnostics.push(
+                definition
+                    .warning(&mod_name, format!("Technology {} overwrites a vanilla technology", key))
+                    .with_label("replaces the whole vanilla definition".to_string())
+                    .with_related(original.related("vanilla definition")),
+            );
+        }
+
+        let data = target.technologies.get(key);
+        if data.is_some_and(|x| x.area == ResearchArea::default()) {
+            diagnostics.push(
+                definition
+                    .warning(&mod_name, format!("Technology {} has no area", key))
+                    .with_label("expected area = physics, society or engineering".to_string()),
+            );
+        }
+
+        // A definition the loader rejected still gets the default keys
+        let default = TechnologyData::default();
+        let (name_keys, desc_keys) = (data.unwrap_or(&default).name_keys(key), data.unwrap_or(&default).desc_keys(key));
+        let mut missing = vec![];
+        for language in &languages {
+            let defined = |candidates: &[String]| candidates.iter().any(|x| keys[*language].contains(x.as_str()));
+            if !defined(&name_keys) {
+                missing.push(format!("{} name", language));
+            }
+            if !defined(&desc_keys) {
+                missing.push(format!("{} description", language));
+            }
+        }
+        if !missing.is_empty() {
+            diagnostics.push(
+                definition
+                    .warning(&mod_name, format!("Technology {} is missing localisation", key))
+                    .with_label(format!("no {}", missing.join(", "))),
+            );
+        }
+    }
+
+    let words = script_words(vfs, &target.path);
+    let mut used: HashSet<&str> = words.iter().map(|x| x.as_str()).collect();
+    used.extend(loaded.iter().flat_map(|x| x.technologies.keys()).map(|x| x.as_str()));
+    used.extend(vanilla.localisations.iter().flat_map(|file| file.entries.keys()).map(|x| x.as_str()));
+    loaded.iter().flat_map(|x| x.localisations.iter()).flat_map(|file| file.entries.values()).for_each(|value| {
+        // Every other piece between `$` is a reference, optionally followed by `|format`
+        used.extend(value.split('$').skip(1).step_by(2).map(|x| x.split('|').next().unwrap_or(x)));
+    });
+
+    // Only cycles starting at a key the mod wins are its own
+    let mut definitions = key_definitions(&loaded);
+    definitions.retain(|_, (_, x)| x.file.starts_with(&target.path));
+    check_references(&loaded, fallback, &definitions, diagnostics);
+
+    let mut seen = HashSet::new();
+    for (_, definition) in localisations.iter().filter(|(_, x)| seen.insert(x.key.as_str())) {
+        if !is_used(&used, &definition.key) {
+            diagnostics.push(
+                definition
+                    .warning(&mod_name, format!("Localisation key {} is never used", definition.key))
+                    .with_label("not referenced by any technology, script file or other localisation".to_string()),
+            );
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vfs::Memory;
+
+    #[tokio::test]
+    async fn lint_reports_mod_problems_but_not_game_ones() {
+        let vfs = Memory::default()
+            .with_file("game/launcher-settings.json", r#"{"rawVersion":"3.6"}"#)
+            .with_file("game/common/scripted_variables/00_variables.txt", "@cost = 100")
+            .with_file("game/common/technology/00_tech.txt", "tech_lasers = { cost = @cost area = physics category = { particles } }")
+            .with_file("game/localisation/english/game_l_english.yml", "l_english:\n tech_lasers:0 \"Lasers\"\n tech_lasers_desc:0 \"Pew\"\n unused_in_game:0 \"Game\"")
+            .with_file("mods/m/descriptor.mod", r#"name="Mod""#)
+            .with_file("mods/m/common/technology/m_tech.txt", [
+                "tech_lasers = { cost = 200 area = physics category = { particles } }",
+                "tech_noarea = { cost = 100 category = { particles } }",
+                "tech_missing = { cost = 100 area = physics category = { particles } }",
+            ].join("\n"))
+            .with_file("mods/m/common/buildings/m_buildings.txt", "building_x = { }")
+            .with_file("mods/m/localisation/english/m_l_english.yml", [
+                "l_english:",
+                " tech_noarea:0 \"No area\"",
+                " tech_noarea_desc:0 \"Nowhere\"",
+                " building_x_desc:0 \"Used through the building\"",
+                " orphan_key:0 \"Nobody\"",
+            ].join("\n"));
+        let diagnostics = Diagnostics::default();
+
+        lint(&vfs, Path::new("mods/m"), Path::new("game"), &FallbackChain::default(), &diagnostics).await.unwrap();
+
+        let report = LintReport::new(Path::new("mods/m"), &diagnostics);
+        let messages: BTreeSet<&str> = report.diagnostics.iter().map(|x| x.message.as_str()).collect();
+        assert_eq!(messages, BTreeSet::from([
+            "Technology tech_lasers overwrites a vanilla technology",
+            "Technology tech_noarea has no area",
+            "Technology tech_missing is missing localisation",
+            "Localisation key orphan_key is never used",
+        ]));
+        assert_eq!((report.errors, report.warnings), (0, 4));
+    }
+
+    #[test]
+    fn keys_derived_from_a_used_name_are_used() {
+        let used = HashSet::from(["building_x"]);
+        assert!(is_used(&used, "building_x"));
+        assert!(is_used(&used, "building_x_desc"));
+        assert!(is_used(&used, "building_x.name"));
+        assert!(!is_used(&used, "building_y_desc"));
+        assert!(!is_used(&used, "building_x_other"));
+    }
+}
@@ -0,0 +1,272 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::ops::Range;
+use log::info;
+use crate::descriptor::LocatedMod;
+use crate::diagnostics::{line_of, Diagnostic, Diagnostics, Related};
+use crate::vfs::Vfs;
+
+/// Descriptor text of one mod, diagnostics point at the field they are about
+struct DescriptorSource<'a> {
+    x: &'a LocatedMod,
+    data: Vec<u8>,
+}
+
+impl<'a> DescriptorSource<'a> {
+    fn read<V: Vfs>(vfs: &V, x: &'a LocatedMod) -> DescriptorSource<'a> {
+        DescriptorSource { x, data: vfs.read(&x.file).unwrap_or_default() }
+    }
+
+    /// First `"text"` or else `text` in the descriptor from byte `from` on, the start of the file if neither is there
+    fn span_from(&self, from: usize, text: &str) -> Range<usize> {
+        let find = |needle: &[u8]| {
+            self.data[from..].windows(needle.len().max(1)).position(|x| x == needle).map(|i| from + i..from + i + needle.len())
+        };
+        find(format!("\"{}\"", text).as_bytes())
+            .or_else(|| find(text.as_bytes()))
+            .unwrap_or(0..0)
+    }
+
+    fn span_of(&self, text: &str) -> Range<usize> {
+        self.span_from(0, text)
+    }
+
+    fn warning(&self, text: &str, message: String) -> Diagnostic {
+        Diagnostic::warning(&self.x.descriptor.name, &self.x.file, &self.data, self.span_of(text), message)
+    }
+
+    /// Warning about `dependency` as listed in `dependencies`, a mod naming itself also has it as `name`
+    fn dependency_warning(&self, dependency: &str, message: String) -> Diagnostic {
+        let from = self.span_of("dependencies").end;
+        Diagnostic::warning(&self.x.descriptor.name, &self.x.file, &self.data, self.span_from(from, dependency), message)
+    }
+
+    fn related(&self, text: &str, message: &str) -> Related {
+        let span = self.span_of(text);
+        Related {
+            file: self.x.file.clone(),
+            line: line_of(&self.data, span.start),
+            offset: span.start,
+            length: span.len(),
+            message: message.to_string(),
+        }
+    }
+}
+
+/// Whether the game at `version`, the `rawVersion` of `launcher-settings.json` like `v3.12.4`,
+/// matches `pattern` from `supported_version` like `v3.12.*`. Parts the pattern leaves out match anything.
+pub fn supports(pattern: &str, version: &str) -> bool {
+    let parts = |x: &str| x.trim().trim_start_matches(['v', 'V']).split('.').map(|x| x.trim().to_string()).collect::<Vec<_>>();
+    let version = parts(version);
+
+    for (i, part) in parts(pattern).iter().enumerate() {
+        if part == "*" {
+            return true;
+        }
+        let matches = match (version.get(i), part.strip_suffix('*')) {
+            (Some(x), Some(prefix)) => x.starts_with(prefix),
+            (Some(x), None) => x == part,
+            (None, _) => false,
+        };
+        if !matches {
+            return false;
+        }
+    }
+    true
+}
+
+/// Warns when `x` declares a `supported_version` the game at `game_version` does not match
+pub fn check_supported_version<V: Vfs>(vfs: &V, x: &LocatedMod, game_version: &str, diagnostics: &Diagnostics) {
+    if let Some(pattern) = &x.descriptor.supported_version {
+        if !supports(pattern, game_version) {
+            diagnostics.push(
+                DescriptorSource::read(vfs, x)
+                    .warning(pattern, format!("{} supports game version {}, the game is {}", x.descriptor.name, pattern, game_version))
+                    .with_label("supported version".to_string()),
+            );
+        }
+    }
+}
+
+/// Mods depending on each other in a cycle, by index, starting at the smallest
+fn find_cycles(dependencies: &[Vec<usize>]) -> BTreeSet<Vec<usize>> {
+    fn visit(i: usize, dependencies: &[Vec<usize>], stack: &mut Vec<usize>, done: &mut HashSet<usize>, cycles: &mut BTreeSet<Vec<usize>>) {
+        if let Some(start) = stack.iter().position(|x| *x == i) {
+            let mut cycle = stack[start..].to_vec();
+            let min = cycle.iter().enumerate().min_by_key(|(_, x)| **x).map_or(0, |(i, _)| i);
+            cycle.rotate_left(min);
+            cycles.insert(cycle);
+            return;
+        }
+        if !done.insert(i) {
+            return;
+        }
+        stack.push(i);
+        for j in &dependencies[i] {
+            visit(*j, dependencies, stack, done, cycles);
+        }
+        stack.pop();
+    }
+
+    let mut cycles = BTreeSet::new();
+    let mut done = HashSet::new();
+    for i in 0..dependencies.len() {
+        visit(i, dependencies, &mut vec![], &mut done, &mut cycles);
+    }
+    cycles
+}
+
+/// Checks `mods` read from `vfs`, in playset order, against the dependencies they declare by name and the game at
+/// `game_version`. Missing dependencies, dependencies loading after the mod needing them and cycles
+/// are reported to `diagnostics`. With `sort` every mod is moved after its dependencies, keeping the
+/// playset order otherwise, instead of reporting the order. Returns the order to load the mods in.
+pub fn resolve<V: Vfs>(vfs: &V, mods: Vec<LocatedMod>, game_version: Option<&str>, sort: bool, diagnostics: &Diagnostics) -> Vec<LocatedMod> {
+    let sources: Vec<DescriptorSource> = mods.iter().map(|x| DescriptorSource::read(vfs, x)).collect();
+    let mut by_name: HashMap<&str, usize> = HashMap::new();
+    for (i, x) in mods.iter().enumerate() {
+        by_name.entry(x.descriptor.name.as_str()).or_insert(i);
+    }
+
+    let dependencies: Vec<Vec<usize>> = mods
+        .iter()
+        .enumerate()
+        .map(|(i, x)| {
+            let mut found = vec![];
+            for dependency in x.descriptor.dependencies.iter().flatten() {
+                match by_name.get(dependency.as_str()) {
+                    // A mod naming itself is a cycle of one, reported with the others
+                    Some(j) => found.push(*j),
+                    None => diagnostics.push(
+                        sources[i]
+                            .warning(dependency, format!("Dependency {} of {} is not in the playset", dependency, x.descriptor.name))
+                            .with_label("required here".to_string()),
+                    ),
+                }
+            }
+            found
+        })
+        .collect();
+
+    let cycles = find_cycles(&dependencies);
+    for cycle in &cycles {
+        let names: Vec<&str> = cycle.iter().chain(cycle.first()).map(|x| mods[*x].descriptor.name.as_str()).collect();
+        if cycle.len() == 1 {
+            diagnostics.push(
+                sources[cycle[0]]
+                    .dependency_warning(names[0], format!("{} lists itself as a dependency", names[0]))
+                    .with_label("its own name".to_string()),
+            );
+            continue;
+        }
+        let mut diagnostic = sources[cycle[0]]
+            .warning(names[1], format!("Mods depend on each other in a cycle: {}", names.join(" -> ")))
+            .with_label(format!("depends on {}", names[1]));
+        for (i, x) in cycle.iter().enumerate().skip(1) {
+            diagnostic = diagnostic.with_related(sources[*x].related(names[i + 1], &format!("depends on {}", names[i + 1])));
+        }
+        diagnostics.push(diagnostic);
+    }
+    let in_cycle: HashSet<usize> = cycles.iter().flatten().copied().collect();
+
+    if let Some(version) = game_version {
+        mods.iter().for_each(|x| check_supported_version(vfs, x, version, diagnostics));
+    }
+
+    if !sort {
+        for (i, found) in dependencies.iter().enumerate() {
+            // A cycle cannot be ordered, it is reported as a whole
+            for j in found.iter().filter(|j| **j > i && !(in_cycle.contains(&i) && in_cycle.contains(j))) {
+                let dependency = &mods[*j].descriptor.name;
+                diagnostics.push(
+                    sources[i]
+                        .warning(dependency, format!("Dependency {} loads after {}", dependency, mods[i].descriptor.name))
+                        .with_label("loads later in the playset, --sort-load-order moves it first".to_string())
+                        .with_related(sources[*j].related(dependency, "loaded from here")),
+                );
+            }
+        }
+        return mods;
+    }
+
+    // Earliest mod whose dependencies are all placed, a cycle is broken at its earliest mod
+    let mut order: Vec<usize> = Vec::with_capacity(mods.len());
+    let mut placed = vec![false; mods.len()];
+    while order.len() < mods.len() {
+        let next = (0..mods.len())
+            .find(|i| !placed[*i] && dependencies[*i].iter().all(|j| placed[*j] || j == i))
+            .or_else(|| (0..mods.len()).find(|i| !placed[*i]))
+            .unwrap();
+        placed[next] = true;
+        order.push(next);
+    }
+
+    let moved = order.iter().enumerate().filter(|(i, x)| *i != **x).count();
+    if moved > 0 {
+        info!("Sorted the load order by dependencies, {} mods moved", moved);
+    }
+
+    let mut mods: Vec<Option<LocatedMod>> = mods.into_iter().map(Some).collect();
+    order.into_iter().filter_map(|i| mods[i].take()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use super::*;
+    use crate::descriptor::locate_mod;
+    use crate::vfs::Memory;
+
+    #[test]
+    fn supports_wildcards_and_exact_versions() {
+        assert!(supports("1.*", "1.5.2"));
+        assert!(supports("*", "3.12.4"));
+        assert!(supports("v3.8.*", "v3.8.4"));
+        assert!(supports("v3.8.*", "3.8.0"));
+        assert!(supports("3.1*", "v3.12.4"));
+        assert!(supports("3.8.4", "v3.8.4"));
+        // Parts the pattern leaves out match anything
+        assert!(supports("v3.8", "3.8.4"));
+    }
+
+    #[test]
+    fn supports_rejects_other_versions() {
+        assert!(!supports("1.*", "2.0.1"));
+        assert!(!supports("v3.8.*", "v3.9.0"));
+        assert!(!supports("3.8.4", "3.8.5"));
+        assert!(!supports("3.8.4", "3.8"));
+    }
+
+    #[test]
+    fn find_cycles_starts_each_cycle_at_its_smallest_mod() {
+        // 0 and 1 need each other, 3 -> 4 -> 5 -> 3, 2 only needs 0
+        let dependencies = vec![vec![1], vec![0], vec![0], vec![4], vec![5], vec![3]];
+        assert_eq!(find_cycles(&dependencies), BTreeSet::from([vec![0, 1], vec![3, 4, 5]]));
+
+        // Entered at 2 through 0, still reported from 1
+        assert_eq!(find_cycles(&[vec![2], vec![2], vec![1]]), BTreeSet::from([vec![1, 2]]));
+    }
+
+    #[test]
+    fn find_cycles_reports_self_dependencies() {
+        assert_eq!(find_cycles(&[vec![0], vec![], vec![1]]), BTreeSet::from([vec![0]]));
+        assert!(find_cycles(&[vec![1], vec![], vec![1, 0]]).is_empty());
+    }
+
+    #[test]
+    fn resolve_reports_self_dependencies() {
+        let descriptor = "name=\"A\"\ndependencies={ \"B\" \"A\" }";
+        let vfs = Memory::default()
+            .with_file("mods/a/descriptor.mod", descriptor)
+            .with_file("mods/b/descriptor.mod", "name=\"B\"");
+        let mods = ["mods/a", "mods/b"].map(|x| locate_mod(&vfs, Path::new(x)).unwrap()).to_vec();
+        let diagnostics = Diagnostics::default();
+
+        let order = resolve(&vfs, mods, None, true, &diagnostics);
+        assert_eq!(order.iter().map(|x| x.descriptor.name.as_str()).collect::<Vec<_>>(), ["B", "A"]);
+
+        let reported = diagnostics.to_vec();
+        assert_eq!(reported.len(), 1);
+        assert_eq!(reported[0].message, "A lists itself as a dependency");
+        // The entry in the list, not the name of the mod
+        assert_eq!(reported[0].offset, descriptor.rfind("\"A\""));
+    }
+}
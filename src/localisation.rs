@@ -1,111 +1,21 @@
-use crate::Serialize;
-use serde::Deserialize;
-use std::collections::{BTreeMap, BTreeSet};
-use std::io::BufRead;
-use std::path::Path;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::{Context, Poll};
 use std::{fs, io};
 use std::str::FromStr;
 use anyhow::anyhow;
-use log::warn;
-use measure_time::trace_time;
 use memmap2::Mmap;
 use rayon::prelude::*;
-use regex::Regex;
-use strum_macros::{Display, EnumIter, EnumString, IntoStaticStr};
-use trim_in_place::TrimInPlace;
-use walkdir::WalkDir;
+use crate::checks::{FileIndex, Finding};
+use crate::descriptor::ReplacedPaths;
+use crate::diagnostics::{ParseError, Reporter};
+use crate::vfs::Vfs;
 use logos_derive::Logos;
+use serde::Serialize;
+use tokio::io::{AsyncRead, ReadBuf};
 
-#[derive(PartialEq, Eq, Debug, Hash, Copy, Clone, Serialize, EnumString, Display, IntoStaticStr, EnumIter)]
-pub enum Languages {
-    #[strum(serialize = "braz_por")]
-    Portuguese,
-
-    #[strum(serialize = "english")]
-    English,
-
-    #[strum(serialize = "french")]
-    French,
-
-    #[strum(serialize = "german")]
-    German,
-
-    #[strum(serialize = "polish")]
-    Polish,
-
-    #[strum(serialize = "russian")]
-    Russian,
-
-    #[strum(serialize = "spanish")]
-    Spanish,
-
-    #[strum(serialize = "simp_chinese")]
-    SimplifiedChinese,
-
-    #[strum(serialize = "default")]
-    Default,
-}
-
-impl Default for Languages {
-    fn default() -> Self {
-        Languages::Default
-    }
-}
-
-pub fn fold_localisation_map(map: &BTreeMap<&str, String>) -> BTreeMap<String, Text> {
-
-    let keys: BTreeSet<&str> = {
-        trace_time!("Mapping localisation keys");
-        map
-            .keys()
-            .map(|x| {
-                x.trim_end_matches("name")
-                    .trim_end_matches("desc")
-                    .trim_end_matches(&[':', '_', '.'])
-            })
-            .collect()
-    };
-
-    let mut ret = BTreeMap::new();
-
-    for key in keys {
-        let name = map
-            .get(format!("{}_name", key).as_str())
-            .or(map.get(format!("{}.name", key).as_str()))
-            .map(|x| x.trim_matches('"'));
-        let desc = map
-            .get(format!("{}_desc", key).as_str())
-            .or(map.get(format!("{}.desc", key).as_str()))
-            .map(|x| x.trim_matches('"'));
-        let value = map
-            .get(key)
-            .map(|x| x.trim_matches('"'))
-            .or(name.clone())
-            .or(desc.clone());
-        ret.insert(
-            key.to_string(),
-            Text {
-                value: value.map(|x| x.to_string()).unwrap_or_default(),
-
-                name: name.map(|x| x.to_string()),
-                description: desc.map(|x| x.to_string()),
-            },
-        );
-    }
-
-    ret
-}
-
-#[derive(PartialEq, Hash, Clone, Default, Debug, Serialize, Deserialize, Eq)]
-pub struct Text {
-    pub value: String,
-
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub name: Option<String>,
-
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub description: Option<String>,
-}
+pub use stellaris_localisation_parser::{split_lines, tokenize_line, Languages, LineDiagnostic, Localisation, LocalisationLine, Text};
 
 #[derive(Logos, Debug, PartialEq)]
 pub enum Token<'a> {
@@ -113,7 +23,9 @@ pub enum Token<'a> {
     #[token("§!")]
     ColorEnd,
 
-    #[regex("\\$\\w+\\$")]
+    /// `$name$` or `$name|Y$` with a format after the `|`, names have no spaces so that
+    /// prices like `50$ and 20$` stay text
+    #[regex("\\$[^$\\s|]+(\\|[^$\\s]+)?\\$")]
     Variable(&'a str),
 
     /// §W §T §L §P §R §S §H §Y §G §E §B §M
@@ -122,9 +34,26 @@ pub enum Token<'a> {
     #[regex("§[WTLPRSHYGEBM]", |lex| ColorCode::from(lex.slice()))]
     ColorCode(ColorCode),
 
-    #[regex(".+")]
+    /// `£minerals£`
+    #[regex("£[^£\n]+£")]
+    Icon(&'a str),
+
+    /// `[Root.GetName]`
+    #[regex("\\[[^\\]\n]+\\]")]
+    Command(&'a str),
+
+    /// A decoded line break or a `\n` escape left in the text
+    #[token("\n")]
+    #[token("\\n")]
+    LineBreak,
+
+    #[regex("[^§$£\\[\\\\\n]+")]
     Text(&'a str),
 
+    /// A markup character not starting valid markup, e.g. an unterminated `$`, read as text
+    #[regex("[§$£\\[\\\\]")]
+    Stray(&'a str),
+
     #[error]
     Error,
 }
@@ -249,182 +178,195 @@ impl From<ColorCode> for &str {
     }
 }
 
-#[derive(PartialEq)]
-enum State {
-    InitOrEnd,
-    ColorStart
-}
-
-pub fn parse_localisation_token(s: &str) {
-    let mut state = State::InitOrEnd;
-
-    let mut vec: Vec<char> = Vec::new();
+/// Languages tried in order when a text is missing in the wanted one, e.g. `--languages simp_chinese,english`.
+/// The default language always comes last.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FallbackChain(Vec<Languages>);
 
+impl Default for FallbackChain {
+    fn default() -> Self {
+        FallbackChain(vec![Languages::SimplifiedChinese, Languages::English, Languages::Default])
+    }
+}
 
-    for ch in s.chars() {
-        match ch {
-            '§' => {
-                if state == State::ColorStart {
-                    warn!("Localisation {} contains §§, report to its author.", s);
-                }
+impl FromStr for FallbackChain {
+    type Err = anyhow::Error;
 
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut languages = vec![];
+        for x in s.split(',').map(|x| x.trim()).filter(|x| !x.is_empty()) {
+            let language = Languages::from_str(x.trim_start_matches("l_")).map_err(|e| anyhow!("{}", e))?;
+            if !languages.contains(&language) {
+                languages.push(language);
             }
-            _ => {
-                match state {
-                    State::InitOrEnd => {
-                        vec.push(ch);
-                    }
-                    State::ColorStart => {
-                        match ch {
-                            'W' | 'T' | 'L' | 'P' | 'R' | 'S' | 'H' | 'Y' | 'G' | 'E' | 'B' | 'M' => {
-
-                            }
-                            _ => {
-                                warn!("Not a valid color code: {}", ch);
-                            }
-                        }
-                    }
-                }
-            }
         }
+        if !languages.contains(&Languages::Default) {
+            languages.push(Languages::Default);
+        }
+        Ok(FallbackChain(languages))
     }
 }
 
-pub fn read_localisations<P: AsRef<Path>>(
-    path: P,
-) -> io::Result<Vec<(Languages, BTreeMap<String, String>)>> {
-    Ok(WalkDir::new(path.as_ref().join("localisation"))
-        .into_iter()
-        .filter_map(|x| {
-            x.ok().filter(|x| {
-                x.path()
-                    .extension()
-                    .and_then(|s| s.to_str())
-                    .map_or(false, |s| s == "yml")
-            })
-        })
-        .collect::<Vec<walkdir::DirEntry>>()
-        .into_par_iter()
-        .map(|x| Ok(parse_localisation(x.path())))
-        .filter_map(
-            |x: Result<anyhow::Result<(Languages, BTreeMap<String, String>)>, io::Error>| match x {
-                Ok(x) => match x {
-                    Ok(x) => Some(x),
-                    Err(e) => {
-                        eprintln!("Localization parsing failed, {:#?}", e);
-                        None
-                    }
-                },
-                Err(e) => {
-                    eprintln!("Localization parsing failed, {:#?}", e);
-                    None
-                }
-            },
-        )
-        //.filter_map(|x: Option<(Languages, BTreeMap<String, String>)>| x)
-        .collect())
-}
+impl FallbackChain {
+    pub fn languages(&self) -> &[Languages] {
+        &self.0
+    }
 
-pub async fn parse_localisation_async(path: &Path) -> anyhow::Result<(Languages, BTreeMap<String, String>)> {
-    use tokio::io::AsyncBufReadExt;
-    let mut map: BTreeMap<String, String> = BTreeMap::new();
+    /// `language` followed by the chain, for looking up a text wanted in `language`
+    pub fn starting_with<'a>(&'a self, language: &'a Languages) -> impl Iterator<Item = &'a Languages> {
+        std::iter::once(language).chain(self.0.iter().filter(move |x| *x != language))
+    }
 
-    let file = tokio::fs::OpenOptions::new()
-        .read(true)
-        .open(path)
-        .await
-        .expect("opening translation failed");
-    let file = tokio::io::BufReader::new(file);
-    let mut lines_iterator = file.lines();
+    /// First value `lookup` finds along the chain
+    pub fn find<'a, T>(&'a self, lookup: impl FnMut(&'a Languages) -> Option<T>) -> Option<T> {
+        self.0.iter().find_map(lookup)
+    }
+}
 
-    let mut current_language = Languages::Default;
+/// Keys of one localisation file of a mod
+#[derive(PartialEq, Debug, Clone, Serialize)]
+pub struct LocalisationFile {
+    pub path: PathBuf,
+    pub language: Languages,
 
-    while let Ok(Some(line)) = lines_iterator.next_line().await {
-        let mut line = line;
+    /// In `localisation/replace/` or `localisation/<language>/replace/`, such keys win over keys of normal files
+    pub replace: bool,
+    pub entries: BTreeMap<String, String>,
 
-        line.trim_in_place();
-        if let Some(comment_index) = line.find('#') {
-            line = (&line[0..comment_index]).to_string();
-        }
-        if line.is_empty() {
-            continue;
-        }
-        if line.starts_with("\u{feff}") {
-            line.drain(..3); // Remove BOM
-        }
+    /// Where the keys are, for the checks
+    #[serde(skip)]
+    pub index: FileIndex,
+}
 
-        if let Some(collon) = line.find(':') {
-            let pair = line.split_at(collon);
-            let key = pair.0.to_owned();
-            let value = pair.1.to_owned();
-            if key.starts_with("l_") && current_language == Languages::Default {
-                current_language = key.trim_start_matches("l_").parse::<Languages>().unwrap_or_default();
-            }
-            if let Some(left) = value.find('"') {
-                if let Some(right) = value.rfind('"') {
-                    map.insert(
-                        key.to_string(),
-                        (&value[left..right]).trim_matches('"').to_string(),
-                    );
-                }
-            }
+impl LocalisationFile {
+    /// `root` is the folder of the mod the file belongs to. The language is the one of the header,
+    /// or the one in the file name for files without a header.
+    pub fn new(root: &Path, path: &Path, ((language, entries), index): (Localisation, FileIndex)) -> LocalisationFile {
+        let language = match language {
+            Languages::Default => path.file_name().and_then(|x| x.to_str()).and_then(Languages::of_file_name).unwrap_or_default(),
+            language => language,
+        };
+        LocalisationFile {
+            path: path.to_path_buf(),
+            language,
+            replace: is_replace(root, path),
+            entries,
+            index,
         }
     }
+}
 
-    Ok((current_language, map))
+/// Whether `file` of the mod in `root` is in a localisation replace folder
+pub fn is_replace(root: &Path, file: &Path) -> bool {
+    // Folders are named ignoring case, as the game does on Windows
+    let folders: Vec<String> = match file.strip_prefix(root).ok().and_then(|x| x.parent()) {
+        Some(x) => x.iter().map(|x| x.to_string_lossy().to_lowercase()).collect(),
+        None => return false,
+    };
+    folders.first().is_some_and(|x| x == "localisation") && folders.iter().skip(1).take(2).any(|x| x == "replace")
 }
 
-pub fn parse_localisation(path: &Path) -> anyhow::Result<(Languages, BTreeMap<String, String>)> {
-    let mut map: BTreeMap<String, String> = BTreeMap::new();
+/// Every localisation file of the mod in `path` outside the `replaced` folders, sorted by path
+pub fn read_localisations<V: Vfs, P: AsRef<Path>>(
+    vfs: &V,
+    path: P,
+    replaced: &ReplacedPaths,
+    reporter: Reporter,
+) -> io::Result<Vec<LocalisationFile>> {
+    let root = path.as_ref();
+    Ok(crate::list_mod_files(vfs, &root.join("localisation"), "yml", true, reporter)?
+        .into_iter()
+        .filter(|x| !replaced.covers(root, x))
+        .collect::<Vec<PathBuf>>()
+        .into_par_iter()
+        .map(|x| crate::cache::cached(vfs, &x, || parse_localisation(vfs, &x)).map(|localisation| LocalisationFile::new(root, &x, localisation)))
+        .filter_map(|x| match x {
+            Ok(x) => Some(x),
+            Err(e) => {
+                reporter.report(e);
+                None
+            }
+        })
+        .collect())
+}
+
+/// Parses the localisation file `path` and indexes it for the checks in one go, lines that do
+/// not parse are left out and kept in the index
+pub fn parse_localisation<V: Vfs>(vfs: &V, path: &Path) -> Result<(Localisation, FileIndex), ParseError> {
+    let parse = |data: &[u8]| {
+        let (localisation, skipped) = stellaris_localisation_parser::parse_slice(data);
+        let mut index = FileIndex::of_localisation_file(path, data);
+        index.findings.extend(skipped.into_iter().map(Finding::skipped));
+        Ok((localisation, index))
+    };
+    let file = match vfs.disk_path(path) {
+        Some(file) => file,
+        None => return parse(&vfs.read(path).map_err(|e| ParseError::io(path, e))?),
+    };
 
     let file = fs::OpenOptions::new()
         .read(true)
-        .open(path)
-        .expect("opening translation failed");
-    let mmap = unsafe { Mmap::map(&file) }?;
-
-    let file = io::BufReader::new(mmap.as_ref());
-    let mut lines_iterator = file.lines();
+        .open(file)
+        .map_err(|e| ParseError::io(path, e))?;
+    let mmap = unsafe { Mmap::map(&file) }.map_err(|e| ParseError::io(path, e))?;
 
-    let mut current_language = Languages::Default;
-
-    let mut i = 0;
-    while let Some(line) = lines_iterator.next() {
-        let mut line_owned = line.expect("There was an error reading a file");
-
-        if i == 0 && line_owned.starts_with("\u{feff}") {
-            line_owned.drain(..3); // Remove BOM
-        }
+    parse(mmap.as_ref())
+}
 
-        line_owned.trim_in_place();
+/// [parse_localisation] for async callers, a file on disk is streamed through the parser without
+/// blocking the runtime, other files are read from `vfs`
+pub async fn parse_localisation_async<V: Vfs>(vfs: &V, path: &Path) -> Result<(Localisation, FileIndex), ParseError> {
+    let file = match vfs.disk_path(path) {
+        Some(file) => file,
+        None => return parse_localisation(vfs, path),
+    };
 
-        if line_owned.is_empty() {
-            continue;
-        }
+    let file = tokio::fs::File::open(file).await.map_err(|e| ParseError::io(path, e))?;
+    let mut reader = tokio::io::BufReader::new(Recorded { inner: file, data: vec![] });
+    let (localisation, skipped) = stellaris_localisation_parser::parse_async_reader(&mut reader)
+        .await
+        .map_err(|e| ParseError::io(path, e))?;
 
-        let mut line = line_owned.as_str();
+    let mut index = FileIndex::of_localisation_file(path, &reader.into_inner().data);
+    index.findings.extend(skipped.into_iter().map(Finding::skipped));
+    Ok((localisation, index))
+}
 
-        if let Some(comment_index) = line_owned.find('#') {
-            line = &line_owned[0..comment_index];
-        }
+/// Keeps a copy of everything read from `inner`, the index is built from the bytes the parser streamed
+struct Recorded<R> {
+    inner: R,
+    data: Vec<u8>,
+}
 
-        if let Some(collon) = line.find(':') {
-            let (key, value) = line.split_at(collon);
-            if current_language == Languages::Default && key.starts_with("l_") {
-                current_language = key.trim_start_matches("l_").parse::<Languages>().unwrap_or_default();
-            }
-            if let Some(left) = value.find('"') {
-                if let Some(right) = value.rfind('"') {
-                    map.insert(
-                        key.to_string(),
-                        (&value[left..right]).trim_matches('"').to_string(),
-                    );
-                }
-            }
+impl<R: AsyncRead + Unpin> AsyncRead for Recorded<R> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let start = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = result {
+            self.data.extend_from_slice(&buf.filled()[start..]);
         }
-
-        i += 1;
+        result
     }
+}
 
-    Ok((current_language, map))
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vfs::Os;
+
+    #[tokio::test]
+    async fn async_parse_matches_the_mapped_parse() {
+        let dir = std::env::temp_dir().join(format!("stellaris-techtree-localisation-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("techs_l_english.yml");
+        fs::write(&path, "\u{feff}l_english:\n tech_a:0 \"§YA§!\"\n broken line\n tech_b:0 \"B\"\n").unwrap();
+
+        let streamed = parse_localisation_async(&Os, &path).await.unwrap();
+        let mapped = parse_localisation(&Os, &path).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(streamed, mapped);
+        assert_eq!(streamed.0.1.len(), 2);
+        assert_eq!(streamed.1.findings.len(), 1);
+    }
 }
@@ -12,270 +12,429 @@ mod localisation;
 mod collection;
 mod tech_tree;
 mod console;
+mod layout;
+mod report;
+mod server;
+mod watch;
+mod cache;
+mod diagnostics;
+mod checks;
+mod archive;
+mod lint;
+mod markup;
+mod resolve;
+mod coverage;
+mod descriptor;
+mod load_order;
+mod vfs;
 
-use itertools::Itertools;
 use rayon::prelude::*;
 
 use tokio_stream::StreamExt;
-use std::collections::{BTreeMap, HashMap, HashSet};
-use std::fs::{read_dir, DirEntry};
+use std::collections::{BTreeMap, HashMap};
 use std::path::{Path, PathBuf};
 
-use std::{fs, io};
+use std::io;
 use std::rc::Rc;
 use datasize::data_size;
 
+use crate::checks::{FileIndex, Finding};
 use crate::data::{StringOrStruct, Technology, TechnologyData, TechnologyNode};
-use crate::localisation::{fold_localisation_map, Languages, Text, read_localisations, Token};
-use jomini::{JominiDeserialize, TextDeserializer};
-use log::info;
-use logos::Lexer;
+use crate::localisation::{FallbackChain, Languages, LocalisationFile, read_localisations};
+use log::{info, warn};
 use measure_time::trace_time;
-use regex::Regex;
 use serde::Serialize;
-use serde_json::{json, Value};
-use tokio_stream::wrappers::ReadDirStream;
+use serde_json::json;
 use crate::tech_tree::TechnologyTree;
+use crate::layout::{LayeringStrategy, Layout, LayoutOptions};
+use crate::server::{ApiState, ModSummary, SharedState};
+use crate::watch::WatchTarget;
+use crate::markup::Markup;
+use crate::resolve::Resolver;
+use crate::coverage::Coverage;
+use crate::descriptor::{read_game_descriptor, LocatedMod, ModDescriptor, ReplacedPaths, GAME_ID};
+use crate::diagnostics::{Diagnostics, ParseError, Reporter, Severity};
+use crate::vfs::Vfs;
+use anyhow::anyhow;
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 
 const WORKSHOP_PATH: &str = "D:\\SteamLibrary\\steamapps\\workshop\\content\\281990";
 
-#[derive(PartialEq, Debug, Serialize)]
+const GAME_PATH: &str = r#"D:\SteamLibrary\steamapps\common\Stellaris"#;
+
+#[derive(PartialEq, Debug, Clone, Serialize)]
 pub struct Mod {
     path: PathBuf,
+
+    /// Stable identity, see [LocatedMod::id]
+    id: String,
     descriptor: ModDescriptor,
     variables: BTreeMap<String, String>,
     technologies: HashMap<String, TechnologyData>,
-    localisations: Vec<(Languages, BTreeMap<String, String>)>,
+    localisations: Vec<LocalisationFile>,
+
+    /// Where things are in the scripted variables and technology files, in file order, for the checks
+    script_files: Vec<FileIndex>,
+
+    /// Folders of this mod that were not read because a later mod replaces them
+    replaced: ReplacedPaths,
+}
+
+impl Mod {
+    /// Mod without any files read yet
+    fn empty(path: PathBuf, id: String, descriptor: ModDescriptor) -> Mod {
+        Mod {
+            path,
+            id,
+            descriptor,
+            variables: BTreeMap::new(),
+            technologies: HashMap::new(),
+            localisations: vec![],
+            script_files: vec![],
+            replaced: ReplacedPaths::default(),
+        }
+    }
+}
+
+/// Files a mod provides with extension `ext` in `dir`, see [Vfs::list_files]. Warns when the
+/// folder or an extension is only matched ignoring case, which works on Windows alone.
+fn list_mod_files<V: Vfs>(vfs: &V, dir: &Path, ext: &str, recursive: bool, reporter: Reporter) -> io::Result<Vec<PathBuf>> {
+    let files = vfs.list_files(dir, ext, recursive)?;
+    for x in vfs::case_folded(dir, ext, &files) {
+        match x.extension().is_some() {
+            true => reporter.warn(&x, format!(
+                "Extension of {} only matches .{} ignoring case, the game finds it on Windows alone",
+                x.display(), ext
+            )),
+            _ => reporter.warn(&x, format!(
+                "Folder {} only matches {} ignoring case, the game finds it on Windows alone",
+                x.display(), dir.display()
+            )),
+        }
+    }
+    Ok(files)
 }
 
-#[derive(JominiDeserialize, PartialEq, Debug, Serialize)]
-struct ModDescriptor {
-    name: String,
+/// Bytes Windows-1252 leaves undefined, along with control characters they give away UTF-16 or damaged files
+fn is_implausible_windows1252(x: u8) -> bool {
+    matches!(x, 0x81 | 0x8d | 0x8f | 0x90 | 0x9d) || (x < 0x20 && !matches!(x, b'\t' | b'\n' | b'\r' | 0x0c))
+}
+
+/// Parses the script file `path` holding `data` the way the game reads it: as UTF-8 if it starts
+/// with a BOM or is valid UTF-8, as Windows-1252 otherwise. A byte giving away another encoding
+/// is added to `index` as a warning, the file is still read as Windows-1252 like the game does.
+/// Only when that fails too the encoding is reported as the error.
+fn parse_script<'a, T: serde::Deserialize<'a>>(path: &Path, data: &'a [u8], index: &mut FileIndex) -> Result<T, ParseError> {
+    if data.starts_with(b"\xef\xbb\xbf") || std::str::from_utf8(data).is_ok() {
+        return jomini::text::de::from_utf8_slice(data).map_err(|e| ParseError::jomini(path, data, e));
+    }
 
-    #[jomini(default)]
-    tags: Vec<String>,
+    let implausible = data.iter().position(|x| is_implausible_windows1252(*x));
+    let parsed = jomini::text::de::from_windows1252_slice(data);
+    match (implausible, parsed) {
+        (Some(offset), Ok(parsed)) => {
+            index.findings.push(Finding {
+                severity: Severity::Warning,
+                line: diagnostics::line_of(data, offset),
+                span: offset..offset + 1,
+                message: format!("Byte 0x{:02x} is neither UTF-8 nor Windows-1252, the file is read as Windows-1252", data[offset]),
+                label: "save the file as UTF-8".to_string(),
+            });
+            Ok(parsed)
+        }
+        // Likely UTF-16 or damaged, the encoding says more than the syntax error
+        (Some(offset), Err(_)) => Err(ParseError::Syntax {
+            file: path.to_path_buf(),
+            line: Some(diagnostics::line_of(data, offset)),
+            offset: Some(offset),
+            message: format!("Byte 0x{:02x} is neither UTF-8 nor Windows-1252, save the file as UTF-8", data[offset]),
+        }),
+        (None, parsed) => parsed.map_err(|e| ParseError::jomini(path, data, e)),
+    }
+}
 
-    version: Option<String>,
-    dependencies: Option<Vec<String>>,
-    picture: Option<String>,
-    supported_version: Option<String>,
+/// Variables of one scripted variables file, with the file indexed for the checks
+type VariableFile = (BTreeMap<String, String>, FileIndex);
 
-    remote_file_id: String,
+fn read_variable_file<V: Vfs>(vfs: &V, path: &Path) -> Result<VariableFile, ParseError> {
+    cache::cached(vfs, path, || {
+        let data = vfs.read(path).map_err(|e| ParseError::io(path, e))?;
+        let mut index = FileIndex { file: path.to_path_buf(), ..Default::default() };
+        let variables = parse_script(path, &data, &mut index)?;
+        Ok((variables, index))
+    })
 }
 
-fn read_variables<P: AsRef<Path>>(path: P) -> io::Result<BTreeMap<String, String>> {
-    Ok(path
-        .as_ref()
-        .join("common")
-        .join("scripted_variables")
-        .read_dir()?
-        .filter_map(|x| {
-            x.ok().filter(|x| {
-                x.path()
-                    .extension()
-                    .and_then(|s| s.to_str())
-                    .map_or(false, |s| s == "txt")
-            })
-        })
-        .collect::<Vec<DirEntry>>()
+fn read_variables<V: Vfs, P: AsRef<Path>>(vfs: &V, path: P, replaced: &ReplacedPaths, reporter: Reporter) -> io::Result<(BTreeMap<String, String>, Vec<FileIndex>)> {
+    if replaced.contains("common/scripted_variables") {
+        return Ok(Default::default());
+    }
+    Ok(list_mod_files(vfs, &path.as_ref().join("common").join("scripted_variables"), "txt", false, reporter)?
         .into_par_iter()
-        .map(|x| {
-            Ok(TextDeserializer::from_windows1252_slice(fs::read(x.path())?.as_slice())
-                .expect(&format!("Parse mod variables failed, {:?}", x.path())))
-        })
-        .filter_map(|x: io::Result<BTreeMap<String, String>> | match x {
-            Ok(x) => Some(x),
+        .map(|x| read_variable_file(vfs, &x))
+        .filter_map(|x| match x {
+            Ok((x, index)) => Some((x, vec![index])),
             Err(e) => {
-                eprintln!("I/O failed, {:#?}", e);
+                reporter.report(e);
                 None
             }
         })
-        .flatten()
-        .collect()
-    )
+        .reduce(Default::default, |(mut variables, mut files), (mut x, mut y)| {
+            variables.append(&mut x);
+            files.append(&mut y);
+            (variables, files)
+        }))
 }
 
-fn read_technologies<P: AsRef<Path>>(path: P) -> io::Result<(BTreeMap<String, String>, HashMap<String, TechnologyData>)> {
-    let (tx, rx) = crossbeam_channel::unbounded();
-    let technologies = path
-        .as_ref()
-        .join("common")
-        .join("technology")
-        .read_dir()?
-        .filter_map(|x| {
-            x.ok().filter(|x| {
-                x.path()
-                    .extension()
-                    .and_then(|s| s.to_str())
-                    .map_or(false, |s| s == "txt")
+/// Variables and technologies of one technology file, with the file indexed for the checks
+type TechnologyFile = (BTreeMap<String, String>, HashMap<String, TechnologyData>, FileIndex);
+
+/// Parses one technology file, top level `@variable = value` pairs are returned separately
+fn read_technology_file<V: Vfs>(vfs: &V, path: &Path) -> Result<TechnologyFile, ParseError> {
+    cache::cached(vfs, path, || {
+        let data = vfs.read(path).map_err(|e| ParseError::io(path, e))?;
+
+        let mut index = FileIndex::of_technology_file(path, &data);
+        let mut variables = BTreeMap::new();
+        let technologies = parse_script::<HashMap<String, StringOrStruct<TechnologyData>>>(path, &data, &mut index)?
+            .into_iter()
+            .filter_map(|(k, v)| match v {
+                StringOrStruct::Str(value) => {
+                    variables.insert(k, value);
+                    None
+                },
+                StringOrStruct::Object(obj) => Some((k, obj)),
             })
-        })
-        .collect::<Vec<DirEntry>>()
+            .collect();
+
+        Ok((variables, technologies, index))
+    })
+}
+
+/// Variables and technologies of every technology file of a mod, with the files indexed for the checks
+type Technologies = (BTreeMap<String, String>, HashMap<String, TechnologyData>, Vec<FileIndex>);
+
+fn read_technologies<V: Vfs, P: AsRef<Path>>(vfs: &V, path: P, replaced: &ReplacedPaths, reporter: Reporter) -> io::Result<Technologies> {
+    if replaced.contains("common/technology") {
+        return Ok(Default::default());
+    }
+    Ok(list_mod_files(vfs, &path.as_ref().join("common").join("technology"), "txt", false, reporter)?
         .into_par_iter()
-        .map(|x| {
-            Ok(TextDeserializer::from_windows1252_slice::<
-                HashMap<String, StringOrStruct<TechnologyData>>,
-            >(fs::read(x.path())?.as_slice())
-                .expect(&format!("Parse mod technology failed, {:?}", x.path()))
-                .into_iter()
-                .filter_map(|(k, v)| match v {
-                    StringOrStruct::Str(value) => {
-                        tx.clone().send((k, value)).expect("Cannot send");
-                        None
-                    },
-                    StringOrStruct::Object(obj) => Some((k, obj)),
-                })
-                .collect())
+        .map(|x| read_technology_file(vfs, &x))
+        .filter_map(|x| match x {
+            Ok((x, y, index)) => Some((x, y, vec![index])),
+            Err(e) => {
+                reporter.report(e);
+                None
+            }
         })
-        .filter_map(|x: io::Result<HashMap<String, TechnologyData>>| match x {
+        .reduce(Default::default, |(mut variables, mut technologies, mut files), (mut x, y, mut z)| {
+            variables.append(&mut x);
+            technologies.extend(y);
+            files.append(&mut z);
+            (variables, technologies, files)
+        }))
+}
+
+/// Descriptors of every mod in `mod_paths`, mod folders or launcher `.mod` files. Those that fail
+/// to parse are reported to `diagnostics` and skipped.
+fn locate_mods<V: Vfs>(vfs: &V, mod_paths: &[String], diagnostics: &Diagnostics) -> Vec<LocatedMod> {
+    mod_paths
+        .iter()
+        .filter_map(|x| match descriptor::locate_mod(vfs, Path::new(x)) {
             Ok(x) => Some(x),
             Err(e) => {
-                eprintln!("I/O failed, {:#?}", e);
+                diagnostics.push(e.into_diagnostic(None));
                 None
             }
         })
-        .flatten()
-        .collect();
-
-    drop(tx); // Disconnect
-
-    Ok((rx.into_iter().collect(), technologies))
+        .collect()
 }
 
-async fn read_mods(mod_paths: &Vec<String>) -> Result<Vec<Mod>, Box<dyn std::error::Error>> {
-    let descriptors = tokio_stream::iter(mod_paths.iter())
-        .then(|x| async move {
-            let path = Path::new(x);
+/// Reads the content of `located`, in load order. Files that fail to parse are reported to
+/// `diagnostics` and skipped, so are folders a later mod replaces.
+async fn read_mods<V: Vfs>(vfs: &V, located: Vec<LocatedMod>, diagnostics: &Diagnostics) -> Result<Vec<Mod>, Box<dyn std::error::Error>> {
+    let replaced: Vec<ReplacedPaths> = (0..located.len())
+        .map(|i| ReplacedPaths::of(located[i + 1..].iter().map(|x| &x.descriptor)))
+        .collect();
 
-            let descriptor: ModDescriptor = {
-                //trace_time!("Parsing descriptor for {:?}", path);
-                TextDeserializer::from_utf8_slice(fs::read(path.join("descriptor.mod"))?.as_slice())
-                    .expect("Parse mod descriptor failed")
-            };
+    let descriptors = tokio_stream::iter(located.into_iter().zip(replaced))
+        .then(|(LocatedMod { path, id, descriptor, .. }, replaced)| async move {
+            let reporter = diagnostics.reporter(&descriptor.name);
 
             let localisations = {
                 //trace_time!("Parsing localisations for {:?}", path);
-                read_localisations(&path).unwrap_or_default()
+                read_localisations(vfs, &path, &replaced, reporter).unwrap_or_default()
             };
 
-            let mut scripted_variables = read_variables(&path).unwrap_or_default();
+            let (mut scripted_variables, mut script_files) = read_variables(vfs, &path, &replaced, reporter).unwrap_or_default();
 
-            let (mut tech_variables, technologies) = {
+            let (mut tech_variables, technologies, mut technology_files) = {
                 //trace_time!("Parsing technologies for {:?}", path);
-                read_technologies(&path).unwrap_or_default()
+                read_technologies(vfs, &path, &replaced, reporter).unwrap_or_default()
             };
 
             scripted_variables.append(&mut tech_variables);
+            script_files.append(&mut technology_files);
 
-            Ok(Mod {
-                path: path.to_path_buf(),
+            Mod {
+                path,
+                id,
                 variables: scripted_variables,
                 technologies,
                 descriptor,
                 localisations,
-            })
+                script_files,
+                replaced,
+            }
         })
-        .filter_map(|x: Result<Mod, io::Error>| x.ok())
         .collect()
         .await;
     Ok(descriptors)
 }
 
-fn parse_game_files<P: AsRef<Path>>(path: P) -> io::Result<Mod> {
+/// Reads the base game, skipping the folders in `replaced`
+fn parse_game_files<V: Vfs, P: AsRef<Path>>(vfs: &V, path: P, replaced: &ReplacedPaths, diagnostics: &Diagnostics) -> io::Result<Mod> {
     let path = path.as_ref();
 
-    let version = serde_json::from_str::<serde_json::Value>(&fs::read_to_string(path.join("launcher-settings.json"))?)
-        .ok()
-        .and_then(|x| x.get("rawVersion").and_then(|y| y.as_str()).map(|y| y.to_string()));
-
-    let descriptor = ModDescriptor {
-        name: "Stellaris".to_string(),
-        tags: vec![],
-        version,
-        dependencies: None,
-        picture: None,
-        supported_version: None,
-        remote_file_id: "Stellaris".to_string(),
-    };
+    let descriptor = read_game_descriptor(vfs, path)?;
+    let reporter = diagnostics.reporter(&descriptor.name);
 
     let localisations = {
         //trace_time!("Parsing localisations for {:?}", path);
-        read_localisations(&path)?
+        read_localisations(vfs, path, replaced, reporter)?
     };
 
-    let mut scripted_variables = read_variables(&path)?;
+    let (mut scripted_variables, mut script_files) = read_variables(vfs, path, replaced, reporter)?;
 
-    let (mut tech_variables, technologies) = {
+    let (mut tech_variables, technologies, mut technology_files) = {
         //trace_time!("Parsing technologies for {:?}", path);
-        read_technologies(&path)?
+        read_technologies(vfs, path, replaced, reporter)?
     };
 
     scripted_variables.append(&mut tech_variables);
+    script_files.append(&mut technology_files);
 
     Ok(Mod {
         path: path.to_path_buf(),
+        id: GAME_ID.to_string(),
         technologies,
         variables: scripted_variables,
         descriptor,
         localisations,
+        script_files,
+        replaced: replaced.clone(),
     })
 }
 
-trait ReplaceVariables<'a> {
-    fn replace_variables<F: FnMut(&'a str) -> &str>(&'a self, f: F) -> String;
-}
+/// Everything the exporters need, technologies carry their bound localisation
+pub struct GameData {
+    mods: Vec<Mod>,
 
-impl<'a> ReplaceVariables<'a> for &'a str {
-    fn replace_variables<F: FnMut(&'a str) -> &str>(&'a self, f: F) -> String {
-        let mut ret = String::new();
-        self.split('$');
-        todo!()
-    }
-}
+    /// Every key with its references resolved
+    localisations: HashMap<Languages, BTreeMap<String, String>>,
 
-impl<'a> ReplaceVariables<'a> for String {
-    fn replace_variables<F: FnMut(&'a str) -> &str>(&'a self, f: F) -> String {
-        todo!()
-    }
+    /// Id of the mod whose value won for every localisation key
+    localisation_providers: HashMap<Languages, BTreeMap<String, String>>,
+    technologies: Vec<Technology>,
+
+    /// Translated technology names and descriptions per mod
+    coverage: Vec<Coverage>,
+
+    /// Languages tried in order for labels and missing references
+    fallback: FallbackChain,
 }
 
+/// Reads the game in `game_path` and the mods in `folders` from `vfs`. With `sort_load_order` mods are
+/// moved after their dependencies, otherwise a playset order breaking them is only reported.
+async fn load_game_data<V: Vfs>(vfs: &V, game_path: &Path, folders: &[String], sort_load_order: bool, fallback: &FallbackChain, diagnostics: &Diagnostics) -> Result<GameData, Box<dyn std::error::Error>> {
+    let game_version = read_game_descriptor(vfs, game_path)?.version;
+    let located = load_order::resolve(vfs, locate_mods(vfs, folders, diagnostics), game_version.as_deref(), sort_load_order, diagnostics);
+    let loaded = {
+        trace_time!("Parse all mods");
+        read_mods(vfs, located, diagnostics).await?
+    };
+    // The game loads first, mods override it in the given order
+    let mut mods = vec![parse_game_files(vfs, game_path, &ReplacedPaths::of(loaded.iter().map(|x| &x.descriptor)), diagnostics)?];
+    mods.extend(loaded);
+    for x in mods.iter().filter(|x| !x.descriptor.replace_path.is_empty()) {
+        info!("{} replaces {}", x.descriptor.name, x.descriptor.replace_path.join(", "));
+    }
+    cache::report();
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let folders = console::query()?;
+    {
+        trace_time!("Check mods");
+        checks::check_mods(&mods, fallback, diagnostics);
+    }
+    if diagnostics.error_count() > 0 {
+        warn!("{} files or lines failed to load and were skipped", diagnostics.error_count());
+    }
 
-    use logos::Logos;
-    let mut lexer: Lexer<Token> = Token::lexer("§Y$matter_decompressor_4$§!扭曲黑洞的引力，形成引力钻头，从奇点中获取£minerals£矿物。");
-    println!("{:#?}", lexer.collect::<Vec<Token>>());
+    Ok(build_game_data(mods, fallback))
+}
 
-    //pretty_env_logger::init();
-    log4rs::init_file("log4rs.yaml", Default::default()).unwrap();
-    info!("Stellaris Tech Tree Parser {}", VERSION);
+/// Winning value of a localisation key and the mod it comes from
+#[derive(Debug, Clone, Copy)]
+pub struct Provided<'a> {
+    pub value: &'a str,
+    pub provider: &'a Mod,
+}
 
-    let mut mods = {
-        trace_time!("Parse all mods");
-        read_mods(&folders).await?
+/// The localisation files of `mods` the game reads, from the lowest priority to the highest.
+/// A file at the same path in a later mod overrides the earlier file as a whole, keys the later file
+/// leaves out are gone too. Of the files left, keys in replace folders win over normal ones, then a
+/// later mod in `mods` wins over earlier ones, then a later file of one mod over earlier files.
+pub fn localisation_files_read(mods: &[Mod]) -> Vec<(&Mod, &LocalisationFile)> {
+    // Paths are compared ignoring case like on Windows, the last file at a path is the one read
+    let relative = |x: &Mod, file: &LocalisationFile| {
+        file.path.strip_prefix(&x.path).unwrap_or(&file.path).to_string_lossy().replace('\\', "/").to_lowercase()
     };
+    let read: HashMap<String, (usize, &Path)> = mods
+        .iter()
+        .enumerate()
+        .flat_map(|(i, x)| x.localisations.iter().map(move |file| (relative(x, file), (i, file.path.as_path()))))
+        .collect();
+
+    let mut files = vec![];
+    for replace in [false, true] {
+        for (i, x) in mods.iter().enumerate() {
+            for file in x.localisations.iter().filter(|file| file.replace == replace) {
+                if read.get(&relative(x, file)) == Some(&(i, file.path.as_path())) {
+                    files.push((x, file));
+                }
+            }
+        }
+    }
+    files
+}
 
-    mods.push(parse_game_files(r#"D:\SteamLibrary\steamapps\common\Stellaris"#)?);
+/// Keys of every language across `mods`, the way the game picks between definitions of one key,
+/// see [`localisation_files_read`]
+pub fn merge_localisations(mods: &[Mod]) -> HashMap<Languages, BTreeMap<&str, Provided<'_>>> {
+    let mut merged: HashMap<Languages, BTreeMap<&str, Provided>> = HashMap::new();
+    for (x, file) in localisation_files_read(mods) {
+        let map = merged.entry(file.language.clone()).or_default();
+        map.extend(file.entries.iter().map(|(k, v)| (k.as_str(), Provided { value: v.as_str(), provider: x })));
+    }
+    merged
+}
 
-    let mods = mods;
+/// Merges parsed mods into technologies and folded localisations
+fn build_game_data(mods: Vec<Mod>, fallback: &FallbackChain) -> GameData {
 
-    let all_localisations: HashMap<Languages, BTreeMap<&str, &str>> = {
+    let all_localisations: HashMap<Languages, BTreeMap<&str, Provided>> = {
         trace_time!("Parse all localisations");
-        mods.iter()
-        .flat_map(|x| x.localisations.iter().map(|(k, v)| (*k, v)))
-        .into_grouping_map()
-        .fold(BTreeMap::new(), |mut acc, _key, val| {
-            val.iter().for_each(|(k, v)| {
-                acc.insert(k.as_str(), v.as_str());
-            });
-            acc
-        })
+        merge_localisations(&mods)
     };
 
+    let localisation_providers: HashMap<Languages, BTreeMap<String, String>> = all_localisations
+        .iter()
+        .map(|(lang, map)| (lang.clone(), map.iter().map(|(k, v)| (k.to_string(), v.provider.id.clone())).collect()))
+        .collect();
+
+    let coverage = coverage::coverage(&mods, &all_localisations);
+
     let all_variables: BTreeMap<&String, &String> = {
         trace_time!("Parse all variables");
         mods
@@ -284,50 +443,22 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             .collect()
     };
 
-    let regex = Regex::new("\\$(\\w+)\\$").unwrap();
-
     let all_localisations: HashMap<Languages, BTreeMap<&str, String>> = {
         trace_time!("Replace variables");
+        let resolver = Resolver::new(&all_localisations, fallback);
         all_localisations
-            .into_iter()
+            .iter()
             .map(|(lang, map)| {
                 let map = map
                     .par_iter()
-                    .map(|(key, value)| {
-                        let mut ret = value.to_string();
-                        for captures in regex.captures_iter(&*value) {
-                            if let Some(variable) = captures.get(1) {
-                                let variable = variable.as_str();
-                                // TODO Replace more variables
-                                if let Some(variable_value) = map.get(variable) {
-                                    ret = ret.replacen(&format!("${}$", variable), variable_value, 1);
-                                }
-                            }
-                        }
-                        (*key, ret)
-                    })
+                    // Cycles are reported by checks::check_references
+                    .map(|(key, x)| (*key, resolver.resolve(lang, key, x.value, &mut vec![])))
                     .collect();
-                (lang, map)
+                (lang.clone(), map)
             })
             .collect()
     };
 
-    let folded_localisations: HashMap<Languages, BTreeMap<String, Text>> = {
-        trace_time!("Fold localisations");
-        all_localisations
-            .par_iter()
-            .map(|(key, value)| (*key, fold_localisation_map(&value)))
-            .collect()
-    };
-
-    {
-        trace_time!("Write localisations");
-        tokio::fs::write(
-            format!("mods/localisation.json"),
-            simd_json::to_string_pretty(&folded_localisations)?,
-        ).await?;
-    }
-
     let all_technologies: Vec<Technology> = {
         trace_time!("Fold technologies");
         mods.par_iter().flat_map(|x| {
@@ -336,13 +467,13 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 .technologies
                 .par_iter()
                 .map(|(name, tech_data)| {
-                    let localisation = folded_localisations
+                    let localisation = all_localisations
                         .iter()
-                        .filter_map(|(lang, map)| Some((lang.clone(), map.get(name)?.clone())))
+                        .filter_map(|(lang, map)| Some((lang.clone(), tech_data.localise(name, map)?)))
                         .collect();
 
                     Technology {
-                        modid: data.descriptor.remote_file_id.to_string(),
+                        modid: data.id.clone(),
                         name: name.to_string(),
                         id: name.to_string(), // TODO
                         localisation,
@@ -378,6 +509,47 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         }).collect()
     };
 
+
+    let localisations = all_localisations
+        .into_iter()
+        .map(|(lang, map)| (lang, map.into_iter().map(|(k, v)| (k.to_string(), v)).collect()))
+        .collect();
+
+    GameData {
+        mods,
+        localisations,
+        localisation_providers,
+        coverage,
+        fallback: fallback.clone(),
+        technologies: all_technologies,
+    }
+}
+
+async fn export(data: &GameData, layout_options: &LayoutOptions) -> Result<(), Box<dyn std::error::Error>> {
+    let mods = &data.mods;
+    let localisations = &data.localisations;
+    let all_technologies = &data.technologies;
+
+    {
+        trace_time!("Write localisations");
+        tokio::fs::write(
+            "mods/localisation.json",
+            simd_json::to_string_pretty(&localisations)?,
+        ).await?;
+        tokio::fs::write(
+            "mods/localisation_providers.json",
+            simd_json::to_string_pretty(&data.localisation_providers)?,
+        ).await?;
+        tokio::fs::write(
+            "mods/coverage.json",
+            simd_json::to_string_pretty(&data.coverage)?,
+        ).await?;
+        tokio::fs::write(
+            "mods/mods.json",
+            simd_json::to_string_pretty(&mods.iter().map(ModSummary::new).collect::<Vec<_>>())?,
+        ).await?;
+    }
+
     let technologies_map: HashMap<&str, Rc<Technology>> = all_technologies.iter().map(|x| (x.id.as_str(), Rc::new(x.clone()))).collect();
 
     let mut tech_tree = TechnologyTree::default();
@@ -386,49 +558,57 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     println!("Tree usage: {} bytes", data_size(&tech_tree));
 
-    {
-        #[derive(Serialize, Hash, Eq, Clone, PartialEq)]
-        struct Link {
-            source: String,
-            target: String
-        }
+    let layout = {
+        trace_time!("Layout tech tree");
+        Layout::compute(&tech_tree, layout_options)
+    };
 
+    {
         let mut nodes = vec![];
-        let mut links = HashSet::new();
         tech_tree.node_map.iter().for_each(|(id, node)| {
             /*if node.data.is_some() {
                 nodes.push(node.data.as_ref().unwrap().clone())
             }*/
-            let label = folded_localisations[&Languages::SimplifiedChinese].get(id)
-                .or(folded_localisations[&Languages::English].get(id))
-                .or(folded_localisations[&Languages::Default].get(id))
-                .map(|x| &x.value);
+            let label = node.data.as_ref()
+                .and_then(|x| data.fallback.find(|lang| x.localisation.get(lang)))
+                .map(|x| Markup::parse(&x.value).to_plain());
            /* let label = node.data.as_ref()
                 .and_then(|x|
                 x.localisation.get(&Languages::SimplifiedChinese).or(x.localisation.get(&Languages::English)).or(x.localisation.get(&Languages::Default))
             ).and_then(|x| x.name.as_ref());*/
-            nodes.push(json!({"id": id, "label": label}));
-
-            node.prev.borrow().iter().for_each(|prev| {
-                if &prev.name != id {
-                    links.insert(Link {
-                        source: prev.name.to_string(),
-                        target: id.to_string()
-                    });
-                }
-            });
-
-
+            let position = layout.nodes.get(id);
+            nodes.push(json!({
+                "id": id,
+                "label": label,
+                "x": position.map(|x| x.x),
+                "y": position.map(|x| x.y),
+                "layer": position.map(|x| x.layer),
+                "lane": position.map(|x| x.lane),
+            }));
         });
 
-        std::fs::write("tech_tree.json", serde_json::to_string(&json!({"nodes": nodes, "links": links}))?)?;
+        // Links carry the routed polyline so the viewer only has to draw
+        std::fs::write("tech_tree.json", serde_json::to_string(&json!({
+            "width": layout.width,
+            "height": layout.height,
+            "node_width": layout::NODE_WIDTH,
+            "node_height": layout::NODE_HEIGHT,
+            "lanes": layout.lanes,
+            "nodes": nodes,
+            "links": layout.edges,
+        }))?)?;
+    }
+
+    {
+        trace_time!("Write HTML report");
+        std::fs::write("tech_tree.html", report::render_html(&format!("Stellaris Tech Tree ({} mods)", mods.len() - 1), &tech_tree, &layout, &data.fallback)?)?;
     }
 
     let technologies_map: HashMap<&str, TechnologyNode> = technologies_map.iter().map(|(id, tech)| {
         (*id, TechnologyNode {
             id: id.to_string(),
             data: tech.clone(),
-            prerequisites: tech.prerequisites.iter().filter_map(|x| technologies_map.get(x.as_str())).map(|x| x.clone()).collect()
+            prerequisites: tech.prerequisites.iter().filter_map(|x| technologies_map.get(x.as_str())).cloned().collect()
         })
     }).collect();
 
@@ -437,7 +617,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     {
         trace_time!("Write technologies");
         tokio::fs::write(
-            format!("mods/all_technologies.json"),
+            "mods/all_technologies.json",
             simd_json::to_string_pretty(&all_technologies)?,
         ).await?;
     }
@@ -445,9 +625,263 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     {
         trace_time!("Write technologies map");
         tokio::fs::write(
-            format!("mods/technologies_map.json"),
+            "mods/technologies_map.json",
             simd_json::to_string_pretty(&technologies_map)?,
         ).await?;
     }
     Ok(())
 }
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+
+    //pretty_env_logger::init();
+    log4rs::init_file("log4rs.yaml", Default::default()).unwrap();
+    info!("Stellaris Tech Tree Parser {}", VERSION);
+
+    if !args.iter().any(|x| x == "--no-cache") {
+        cache::init(None);
+    }
+
+    let vfs = archive::Mounted::new(vfs::Os);
+    let diagnostics = Diagnostics::default();
+    let strict = args.iter().any(|x| x == "--strict");
+    let sort_load_order = args.iter().any(|x| x == "--sort-load-order");
+    let fallback: FallbackChain = flag_value(&args, "--languages").map(|x| x.parse()).transpose()?.unwrap_or_default();
+
+    let layout_options = LayoutOptions {
+        layering: if args.iter().any(|x| x == "--layer-by-tier") {
+            LayeringStrategy::Tier
+        } else {
+            LayeringStrategy::LongestPath
+        },
+        ..Default::default()
+    };
+
+    match args.first().map(|x| x.as_str()) {
+        Some("lint") => {
+            let mod_dir = positional(&args)
+                .map(PathBuf::from)
+                .ok_or_else(|| anyhow!("Usage: lint <mod-dir or launcher .mod file> [--game <path>] [--json <path>] [--strict]"))?;
+            let game_dir = flag_value(&args, "--game").unwrap_or(GAME_PATH);
+            let report = flag_value(&args, "--json").unwrap_or(lint::DEFAULT_REPORT);
+
+            lint::lint(&vfs, &mod_dir, Path::new(game_dir), &fallback, &diagnostics).await?;
+            emit_diagnostics(&vfs, &diagnostics);
+            lint::LintReport::new(&mod_dir, &diagnostics).write(Path::new(report))?;
+
+            // Lint findings are warnings, --strict makes them fail the build too
+            match (diagnostics.error_count(), diagnostics.warning_count()) {
+                (errors, _) if errors > 0 => return Err(anyhow!("Lint found {} errors", errors).into()),
+                (_, warnings) if strict && warnings > 0 => return Err(anyhow!("Lint found {} warnings, failing because of --strict", warnings).into()),
+                _ => {}
+            }
+        }
+        Some("serve") => {
+            let folders = mod_folders(&vfs, &args)?;
+            let addr = positional(&args)
+                .unwrap_or(server::DEFAULT_ADDR)
+                .parse()?;
+
+            if args.iter().any(|x| x == "--watch") {
+                let state = SharedState::default();
+                tokio::try_join!(
+                    server::serve(state.clone(), addr),
+                    watch::watch(&vfs, Path::new(GAME_PATH), &folders, sort_load_order, &fallback, WatchTarget::Serve(state)),
+                )?;
+            } else {
+                let data = load_game_data(&vfs, Path::new(GAME_PATH), &folders, sort_load_order, &fallback, &diagnostics).await?;
+                emit_diagnostics(&vfs, &diagnostics);
+                check_strict(&diagnostics, strict)?;
+                server::serve(SharedState::new(ApiState::new(data)), addr).await?;
+            }
+        }
+        Some("watch") => watch::watch(&vfs, Path::new(GAME_PATH), &mod_folders(&vfs, &args)?, sort_load_order, &fallback, WatchTarget::Export(layout_options)).await?,
+        _ => {
+            let folders = mod_folders(&vfs, &args)?;
+            let data = load_game_data(&vfs, Path::new(GAME_PATH), &folders, sort_load_order, &fallback, &diagnostics).await?;
+            emit_diagnostics(&vfs, &diagnostics);
+            export(&data, &layout_options).await?;
+            check_strict(&diagnostics, strict)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Flags followed by a value
+const VALUE_FLAGS: [&str; 5] = ["--game", "--json", "--languages", "--import", "--workshop"];
+
+/// Value following `name` on the command line, e.g. `--game <path>`
+fn flag_value<'a>(args: &'a [String], name: &str) -> Option<&'a str> {
+    args.iter().position(|x| x == name).and_then(|i| args.get(i + 1)).map(|x| x.as_str())
+}
+
+/// First argument after the command that is neither a flag nor the value of one
+fn positional(args: &[String]) -> Option<&str> {
+    (1..args.len())
+        .find(|i| !args[*i].starts_with("--") && !VALUE_FLAGS.contains(&args[i - 1].as_str()))
+        .map(|i| args[i].as_str())
+}
+
+/// Mods to load, from a shared playset with `--import <file>` or else picked interactively
+fn mod_folders<V: Vfs>(vfs: &V, args: &[String]) -> anyhow::Result<Vec<String>> {
+    let file = match flag_value(args, "--import") {
+        Some(file) => file,
+        None => return console::query(vfs),
+    };
+
+    let workshop_path = flag_value(args, "--workshop").unwrap_or(WORKSHOP_PATH);
+    let playset = collection::import_playset(vfs, file, Path::new(workshop_path))?;
+    println!("Importing playset {} with {} mods", playset.name, playset.sources.len());
+    for x in &playset.missing {
+        warn!("{} is not installed in {}, skipping it", x, workshop_path);
+    }
+    Ok(playset.sources)
+}
+
+/// Writes the diagnostics found while loading to the current folder, a failure is only logged
+fn emit_diagnostics<V: Vfs>(vfs: &V, diagnostics: &Diagnostics) {
+    if let Err(e) = diagnostics.emit(vfs, Path::new(".")) {
+        warn!("Writing diagnostics failed, {}", e);
+    }
+}
+
+/// With `--strict` any error found while loading fails the run, after the outputs were written
+fn check_strict(diagnostics: &Diagnostics, strict: bool) -> anyhow::Result<()> {
+    match diagnostics.error_count() {
+        errors if strict && errors > 0 => Err(anyhow!("{} errors while loading, failing because of --strict", errors)),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use itertools::Itertools;
+    use super::*;
+    use crate::archive::Mounted;
+    use crate::checks::FileIndex;
+    use crate::vfs::Memory;
+
+    /// Mod in `path` with localisation files of `(path in the mod, keys)`
+    fn localised(path: &str, files: &[(&str, &[(&str, &str)])]) -> Mod {
+        let root = PathBuf::from(path);
+        Mod {
+            localisations: files
+                .iter()
+                .map(|(file, keys)| {
+                    let entries = keys.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+                    LocalisationFile::new(&root, &root.join(file), ((Languages::English, entries), FileIndex::default()))
+                })
+                .collect(),
+            ..Mod::empty(root.clone(), path.to_string(), ModDescriptor::named(path))
+        }
+    }
+
+    #[test]
+    fn merge_overrides_files_then_keys() {
+        let mods = [
+            localised("game", &[
+                ("localisation/english/techs_l_english.yml", &[("a", "game a"), ("b", "game b")]),
+                ("localisation/english/other_l_english.yml", &[("c", "game c")]),
+            ]),
+            localised("first", &[
+                // Same file as in the game, b goes with it
+                ("Localisation/english/techs_l_english.yml", &[("a", "first a")]),
+                ("localisation/english/replace/r_l_english.yml", &[("c", "replaced c")]),
+            ]),
+            localised("second", &[
+                ("localisation/english/z_l_english.yml", &[("a", "second a"), ("c", "second c")]),
+                ("localisation/english/zz_l_english.yml", &[("a", "second later a")]),
+            ]),
+        ];
+
+        let merged = merge_localisations(&mods);
+        let english: BTreeMap<&str, (&str, &str)> = merged[&Languages::English]
+            .iter()
+            .map(|(k, x)| (*k, (x.value, x.provider.id.as_str())))
+            .collect();
+        assert_eq!(english, BTreeMap::from([("a", ("second later a", "second")), ("c", ("replaced c", "first"))]));
+    }
+
+    /// Zip holding `files` as `(path inside the zip, content)`
+    fn zipped(files: &[(&str, &str)]) -> Vec<u8> {
+        let mut zip = zip::ZipWriter::new(io::Cursor::new(vec![]));
+        for (path, content) in files {
+            zip.start_file(*path, zip::write::FileOptions::default()).unwrap();
+            zip.write_all(content.as_bytes()).unwrap();
+        }
+        zip.finish().unwrap().into_inner()
+    }
+
+    /// A game, a mod overriding some of it, one replacing its technologies and one packed in a zip
+    fn installed() -> Mounted<Memory> {
+        Mounted::new(
+            Memory::default()
+                .with_file("game/launcher-settings.json", r#"{"rawVersion":"3.6"}"#)
+                .with_file("game/common/scripted_variables/00_variables.txt", "@tier1cost = 100")
+                .with_file("game/common/technology/00_tech.txt", "tech_a = { cost = @tier1cost area = physics category = { particles } }\ntech_b = { cost = @tier1cost area = society category = { biology } }")
+                .with_file("game/localisation/english/game_l_english.yml", "l_english:\n tech_a:0 \"Game A\"\n tech_b:0 \"Game B\"")
+                .with_file("mods/over/descriptor.mod", r#"name="Override""#)
+                .with_file("mods/over/common/technology/over_tech.txt", "tech_a = { cost = 200 area = physics category = { particles } }")
+                .with_file("mods/over/localisation/english/over_l_english.yml", "l_english:\n tech_a:0 \"Override A\"")
+                .with_file("mods/replacer/descriptor.mod", "name=\"Replacer\"\nreplace_path=\"common/technology\"")
+                .with_file("mods/replacer/common/technology/new_tech.txt", "tech_c = { cost = 300 area = engineering category = { industry } }")
+                .with_file("mods/zipped.zip", zipped(&[
+                    ("descriptor.mod", r#"name="Zipped""#),
+                    ("common/technology/z_tech.txt", "tech_z = { cost = 400 area = physics category = { computing } }"),
+                    ("localisation/english/z_l_english.yml", "l_english:\n tech_z:0 \"Zipped Z\""),
+                ])),
+        )
+    }
+
+    /// Sorted technology keys of every mod read
+    fn technology_keys(mods: &[Mod]) -> Vec<Vec<&str>> {
+        mods.iter().map(|x| x.technologies.keys().map(|x| x.as_str()).sorted().collect()).collect()
+    }
+
+    #[tokio::test]
+    async fn read_mods_reads_folders_and_archives() {
+        let vfs = installed();
+        let diagnostics = Diagnostics::default();
+        let located = locate_mods(&vfs, &["mods/over".to_string(), "mods/zipped.zip".to_string()], &diagnostics);
+        let mods = read_mods(&vfs, located, &diagnostics).await.unwrap();
+
+        assert_eq!(mods.iter().map(|x| x.descriptor.name.as_str()).collect::<Vec<_>>(), ["Override", "Zipped"]);
+        assert_eq!(technology_keys(&mods), [["tech_a"], ["tech_z"]]);
+        assert_eq!(mods[1].localisations[0].entries["tech_z"], "Zipped Z");
+        assert_eq!(diagnostics.error_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn read_mods_skips_folders_a_later_mod_replaces() {
+        let vfs = installed();
+        let diagnostics = Diagnostics::default();
+        let located = locate_mods(&vfs, &["mods/over".to_string(), "mods/replacer".to_string()], &diagnostics);
+        let mods = read_mods(&vfs, located, &diagnostics).await.unwrap();
+
+        assert_eq!(technology_keys(&mods), [vec![], vec!["tech_c"]]);
+        // Only the replaced folder is skipped
+        assert_eq!(mods[0].localisations.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn load_game_data_overrides_the_game() {
+        let vfs = installed();
+        let diagnostics = Diagnostics::default();
+        let folders = ["mods/replacer", "mods/over", "mods/zipped.zip"].map(|x| x.to_string());
+        let data = load_game_data(&vfs, Path::new("game"), &folders, false, &FallbackChain::default(), &diagnostics).await.unwrap();
+
+        // The game technologies are replaced, those of mods loading after the replacing one stay
+        let costs: BTreeMap<&str, u64> = data.technologies.iter().map(|x| (x.id.as_str(), x.cost)).collect();
+        assert_eq!(costs, BTreeMap::from([("tech_a", 200), ("tech_c", 300), ("tech_z", 400)]));
+
+        let english = &data.localisations[&Languages::English];
+        assert_eq!(english["tech_a"], "Override A");
+        assert_eq!(english["tech_b"], "Game B");
+        assert_eq!(english["tech_z"], "Zipped Z");
+        assert_eq!(diagnostics.error_count(), 0);
+    }
+}
@@ -0,0 +1,260 @@
+use logos::Logos;
+use crate::localisation::{ColorCode, Token};
+use crate::report::html_escape;
+
+/// Piece of localised text, see https://stellaris.paradoxwikis.com/Localisation_modding
+#[derive(Debug, Clone, PartialEq)]
+pub enum Node {
+    Text(String),
+
+    /// `§Y...§!`, a span left open runs to the end of the text
+    Colored(ColorCode, Vec<Node>),
+
+    /// `$name$` or `$name|Y$`
+    Variable {
+        name: String,
+        format: Option<String>,
+    },
+
+    /// `£minerals£`
+    Icon(String),
+
+    /// `[Root.GetName]`, evaluated by the game at runtime
+    Command(String),
+
+    LineBreak,
+}
+
+/// Parsed markup of one localisation value
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Markup(pub Vec<Node>);
+
+/// Name and format of a `$name|format$` token
+pub fn reference(token: &str) -> (&str, Option<&str>) {
+    let x = &token[1..token.len() - 1];
+    match x.split_once('|') {
+        Some((name, format)) => (name, Some(format)),
+        None => (x, None),
+    }
+}
+
+/// Appends `node`, merging adjacent text
+fn push(nodes: &mut Vec<Node>, node: Node) {
+    if let (Some(Node::Text(last)), Node::Text(text)) = (nodes.last_mut(), &node) {
+        last.push_str(text);
+    } else {
+        nodes.push(node);
+    }
+}
+
+impl Markup {
+    pub fn parse(s: &str) -> Markup {
+        let mut root = vec![];
+        let mut stack: Vec<(ColorCode, Vec<Node>)> = vec![];
+
+        let mut lexer = Token::lexer(s);
+        while let Some(token) = lexer.next() {
+            let node = match token {
+                Token::ColorCode(color) => {
+                    stack.push((color, vec![]));
+                    continue;
+                }
+                Token::ColorEnd => {
+                    // A stray §! is ignored like the game does
+                    if let Some((color, children)) = stack.pop() {
+                        push(stack.last_mut().map_or(&mut root, |x| &mut x.1), Node::Colored(color, children));
+                    }
+                    continue;
+                }
+                Token::Variable(x) => {
+                    let (name, format) = reference(x);
+                    Node::Variable { name: name.to_string(), format: format.map(|x| x.to_string()) }
+                }
+                Token::Icon(x) => Node::Icon(x.trim_matches('£').to_string()),
+                Token::Command(x) => Node::Command(x[1..x.len() - 1].to_string()),
+                Token::LineBreak => Node::LineBreak,
+                Token::Text(x) | Token::Stray(x) => Node::Text(x.to_string()),
+                // The lexer gives up on the rest of a value after a `$`, `£` or `[` that is never closed
+                Token::Error => Node::Text(lexer.slice().to_string()),
+            };
+            push(stack.last_mut().map_or(&mut root, |x| &mut x.1), node);
+        }
+
+        while let Some((color, children)) = stack.pop() {
+            push(stack.last_mut().map_or(&mut root, |x| &mut x.1), Node::Colored(color, children));
+        }
+
+        Markup(root)
+    }
+
+    /// Text without colours and icons, references are kept as written
+    pub fn to_plain(&self) -> String {
+        fn render(nodes: &[Node], out: &mut String) {
+            for node in nodes {
+                match node {
+                    Node::Text(x) => out.push_str(x),
+                    Node::Colored(_, children) => render(children, out),
+                    Node::Variable { name, .. } => {
+                        out.push('$');
+                        out.push_str(name);
+                        out.push('$');
+                    }
+                    Node::Icon(_) => {}
+                    Node::Command(x) => {
+                        out.push('[');
+                        out.push_str(x);
+                        out.push(']');
+                    }
+                    Node::LineBreak => out.push('\n'),
+                }
+            }
+        }
+
+        let mut out = String::new();
+        render(&self.0, &mut out);
+        out
+    }
+
+    /// HTML fragment, colours become inline styles and icons, references and commands get a class to style
+    pub fn to_html(&self) -> String {
+        fn render(nodes: &[Node], out: &mut String) {
+            for node in nodes {
+                match node {
+                    Node::Text(x) => out.push_str(&html_escape(x)),
+                    Node::Colored(color, children) => {
+                        out.push_str(&format!("<span style=\"color: {}\">", color.hex()));
+                        render(children, out);
+                        out.push_str("</span>");
+                    }
+                    Node::Variable { name, .. } => out.push_str(&format!("<span class=\"variable\">${}$</span>", html_escape(name))),
+                    Node::Icon(x) => out.push_str(&format!("<span class=\"icon icon-{0}\" title=\"{0}\"></span>", html_escape(x))),
+                    Node::Command(x) => out.push_str(&format!("<span class=\"command\">[{}]</span>", html_escape(x))),
+                    Node::LineBreak => out.push_str("<br>"),
+                }
+            }
+        }
+
+        let mut out = String::new();
+        render(&self.0, &mut out);
+        out
+    }
+
+    /// Text with 24-bit ANSI colour escapes for terminals
+    pub fn to_ansi(&self) -> String {
+        fn render(nodes: &[Node], colors: &mut Vec<ColorCode>, out: &mut String) {
+            for node in nodes {
+                match node {
+                    Node::Colored(color, children) => {
+                        colors.push(*color);
+                        out.push_str(&ansi(*color));
+                        render(children, colors, out);
+                        colors.pop();
+                        // Back to the enclosing colour
+                        out.push_str(&colors.last().map_or_else(|| "\x1b[0m".to_string(), |x| ansi(*x)));
+                    }
+                    Node::Icon(_) => {}
+                    _ => out.push_str(&Markup(vec![node.clone()]).to_plain()),
+                }
+            }
+        }
+
+        fn ansi(color: ColorCode) -> String {
+            let (r, g, b) = color.rgb();
+            format!("\x1b[38;2;{};{};{}m", r, g, b)
+        }
+
+        let mut out = String::new();
+        render(&self.0, &mut vec![], &mut out);
+        out
+    }
+}
+
+impl ColorCode {
+    /// Approximation of the in-game colour
+    pub fn rgb(self) -> (u8, u8, u8) {
+        match self {
+            ColorCode::White => (255, 255, 255),
+            ColorCode::LightGrey => (176, 176, 176),
+            ColorCode::Brown => (195, 176, 145),
+            ColorCode::LightRed => (255, 122, 122),
+            ColorCode::Red => (226, 59, 59),
+            ColorCode::DarkOrange => (227, 107, 28),
+            ColorCode::Orange => (245, 166, 35),
+            ColorCode::Yellow => (255, 225, 77),
+            ColorCode::Green => (77, 203, 92),
+            ColorCode::Teal => (62, 211, 199),
+            ColorCode::Blue => (90, 160, 255),
+            ColorCode::Purple => (176, 108, 255),
+            ColorCode::Default => (220, 230, 238),
+        }
+    }
+
+    pub fn hex(self) -> String {
+        let (r, g, b) = self.rgb();
+        format!("#{:02x}{:02x}{:02x}", r, g, b)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(x: &str) -> Node {
+        Node::Text(x.to_string())
+    }
+
+    #[test]
+    fn parse_nests_colours_and_splits_references() {
+        let markup = Markup::parse("§Yyellow $tech_x|Y$ £minerals£§! [Root.GetName]\\nend");
+        assert_eq!(markup.0, vec![
+            Node::Colored(ColorCode::Yellow, vec![
+                text("yellow "),
+                Node::Variable { name: "tech_x".to_string(), format: Some("Y".to_string()) },
+                text(" "),
+                Node::Icon("minerals".to_string()),
+            ]),
+            text(" "),
+            Node::Command("Root.GetName".to_string()),
+            Node::LineBreak,
+            text("end"),
+        ]);
+    }
+
+    #[test]
+    fn parse_keeps_dollar_signs_around_spaces_as_text() {
+        let markup = Markup::parse("Costs 50$ and 20$, $tech_x$");
+        assert_eq!(markup.to_plain(), "Costs 50$ and 20$, $tech_x$");
+        assert_eq!(markup.0.iter().filter(|x| matches!(x, Node::Variable { .. })).count(), 1);
+    }
+
+    #[test]
+    fn parse_closes_open_colours_at_the_end() {
+        assert_eq!(Markup::parse("§Ra §Gb").0, vec![
+            Node::Colored(ColorCode::Red, vec![text("a "), Node::Colored(ColorCode::Green, vec![text("b")])]),
+        ]);
+    }
+
+    #[test]
+    fn parse_keeps_stray_markup_as_text() {
+        assert_eq!(Markup::parse("50$ of [it").0, vec![text("50$ of [it")]);
+        assert_eq!(Markup::parse("a §! b £c").0, vec![text("a  b £c")]);
+    }
+
+    #[test]
+    fn to_html_escapes_text_and_names() {
+        assert_eq!(
+            Markup::parse("<b>&\"x\"</b> $a<b>$").to_html(),
+            "&lt;b&gt;&amp;&quot;x&quot;&lt;/b&gt; <span class=\"variable\">$a&lt;b&gt;$</span>",
+        );
+        assert_eq!(Markup::parse("§Gok§!").to_html(), format!("<span style=\"color: {}\">ok</span>", ColorCode::Green.hex()));
+    }
+
+    #[test]
+    fn to_ansi_restores_the_enclosing_colour() {
+        let (red, green) = ("\x1b[38;2;226;59;59m", "\x1b[38;2;77;203;92m");
+        assert_eq!(
+            Markup::parse("a §Rred §Ggreen§! red§! plain £energy£").to_ansi(),
+            format!("a {red}red {green}green{red} red\x1b[0m plain "),
+        );
+    }
+}
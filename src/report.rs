@@ -0,0 +1,144 @@
+use std::collections::{BTreeMap, BTreeSet};
+use serde::Serialize;
+use crate::layout::{EdgeRoute, Lane, Layout, NODE_HEIGHT, NODE_WIDTH};
+use crate::localisation::FallbackChain;
+use crate::markup::Markup;
+use crate::tech_tree::TechnologyTree;
+
+const TEMPLATE: &str = include_str!("report.html");
+
+#[derive(Serialize)]
+struct ReportNode<'a> {
+    id: &'a str,
+    x: f64,
+    y: f64,
+    area: Option<&'a str>,
+    tier: Option<&'a str>,
+    modid: Option<&'a str>,
+
+    /// Technology name per language, markup stripped
+    labels: BTreeMap<&'a str, String>,
+
+    /// Technology description per language as an HTML fragment
+    descriptions: BTreeMap<&'a str, String>,
+}
+
+#[derive(Serialize)]
+struct ReportData<'a> {
+    title: &'a str,
+    width: f64,
+    height: f64,
+    node_width: f64,
+    node_height: f64,
+    languages: Vec<&'a str>,
+
+    /// Languages a missing label is taken from, in order
+    fallback: Vec<&'a str>,
+    lanes: &'a [Lane],
+    nodes: Vec<ReportNode<'a>>,
+    edges: &'a [EdgeRoute],
+}
+
+/// Renders the tree as a single self-contained HTML page, the data is inlined so the file can be shared as is.
+pub fn render_html(title: &str, tree: &TechnologyTree, layout: &Layout, fallback: &FallbackChain) -> anyhow::Result<String> {
+    let mut languages = BTreeSet::new();
+    let mut nodes = vec![];
+
+    for (id, node) in &tree.node_map {
+        let position = match layout.nodes.get(id) {
+            Some(position) => position,
+            None => continue,
+        };
+
+        let labels: BTreeMap<&str, String> = node.data.as_ref().map(|x| {
+            x.localisation
+                .iter()
+                .filter_map(|(lang, text)| {
+                    let label = text.name.as_deref().unwrap_or(&text.value);
+                    if label.is_empty() {
+                        return None;
+                    }
+                    languages.insert(lang);
+                    Some((lang.as_str(), Markup::parse(label).to_plain()))
+                })
+                .collect()
+        }).unwrap_or_default();
+
+        let descriptions: BTreeMap<&str, String> = node.data.as_ref().map(|x| {
+            x.localisation
+                .iter()
+                .filter_map(|(lang, text)| text.description.as_deref().map(|x| (lang.as_str(), Markup::parse(x).to_html())))
+                .collect()
+        }).unwrap_or_default();
+
+        nodes.push(ReportNode {
+            id,
+            x: position.x,
+            y: position.y,
+            area: node.data.as_ref().map(|x| x.area.as_ref()),
+            tier: node.data.as_ref().and_then(|x| x.tier.as_deref()),
+            modid: node.data.as_ref().map(|x| x.modid.as_str()),
+            labels,
+            descriptions,
+        });
+
+    }
+
+    let data = ReportData {
+        title,
+        width: layout.width,
+        height: layout.height,
+        node_width: NODE_WIDTH,
+        node_height: NODE_HEIGHT,
+        languages: languages.into_iter().map(|x| x.as_str()).collect(),
+        fallback: fallback.languages().iter().map(|x| x.as_str()).collect(),
+        lanes: &layout.lanes,
+        nodes,
+        edges: &layout.edges,
+    };
+
+    // Keep the inlined JSON from closing the surrounding <script> tag
+    let json = serde_json::to_string(&data)?.replace("</", "<\\/");
+
+    Ok(TEMPLATE
+        .replace("{{title}}", &html_escape(title))
+        .replace("{{data}}", &json))
+}
+
+pub fn html_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::rc::Rc;
+    use crate::data::Technology;
+    use crate::layout::LayoutOptions;
+    use crate::localisation::{Languages, Text};
+
+    #[test]
+    fn render_html_keeps_localisation_inside_the_script_tag() {
+        let label = "</script><script>alert('x')</script>";
+        let tech = Rc::new(Technology {
+            id: "tech_x".to_string(),
+            tier: Some("0".to_string()),
+            localisation: HashMap::from([(Languages::English, Text { value: label.to_string(), name: Some(label.to_string()), description: None })]),
+            ..Default::default()
+        });
+        let mut tree = TechnologyTree::default();
+        tree.insert_map(&HashMap::from([("tech_x", tech)]));
+        let layout = Layout::compute(&tree, &LayoutOptions::default());
+
+        let html = render_html("Mod's <tree>", &tree, &layout, &FallbackChain::default()).unwrap();
+
+        assert_eq!(html.matches("</script>").count(), TEMPLATE.matches("</script>").count());
+        assert!(html.contains(r#"<\/script><script>alert('x')<\/script>"#));
+        assert!(html.contains("<title>Mod&#39;s &lt;tree&gt;</title>"));
+    }
+}
@@ -0,0 +1,184 @@
+use std::collections::{BTreeMap, HashMap};
+use logos::Logos;
+use crate::localisation::{FallbackChain, Languages, Token};
+use crate::markup::reference;
+use crate::Provided;
+
+/// Keys referring to each other through `$key$`, in reference order, with the language each was found in
+pub type Cycle = Vec<(Languages, String)>;
+
+/// Written in place of a reference that names no key or leads back into itself
+pub fn unresolved(name: &str) -> String {
+    format!("{{?{}}}", name)
+}
+
+/// Expands `$key$` references in localisation values, `maps` holds the merged keys of every language
+/// and a key missing in the language of the text is looked up along `fallback`
+pub struct Resolver<'a> {
+    maps: &'a HashMap<Languages, BTreeMap<&'a str, Provided<'a>>>,
+    fallback: &'a FallbackChain,
+}
+
+impl<'a> Resolver<'a> {
+    pub fn new(maps: &'a HashMap<Languages, BTreeMap<&'a str, Provided<'a>>>, fallback: &'a FallbackChain) -> Resolver<'a> {
+        Resolver { maps, fallback }
+    }
+
+    /// Value of `key` in `language` or else in the first fallback language defining it
+    fn lookup(&self, language: &Languages, key: &str) -> Option<(&'a Languages, &'a str)> {
+        self.fallback
+            .starting_with(language)
+            .find_map(|x| self.maps.get_key_value(x).and_then(|(x, map)| map.get(key).map(|provided| (x, provided.value))))
+    }
+
+    /// `value` of `key` with every reference expanded recursively, references are looked up in
+    /// `language` first. Cycles met on the way are added to `cycles` and left unresolved.
+    pub fn resolve<'s>(&'s self, language: &'s Languages, key: &'s str, value: &'s str, cycles: &mut Vec<Cycle>) -> String {
+        let mut out = String::with_capacity(value.len());
+        self.expand(language, value, &mut vec![(language, key)], cycles, &mut out);
+        out
+    }
+
+    fn expand<'s>(
+        &'s self,
+        language: &'s Languages,
+        value: &'s str,
+        stack: &mut Vec<(&'s Languages, &'s str)>,
+        cycles: &mut Vec<Cycle>,
+        out: &mut String,
+    ) {
+        if !value.contains('$') {
+            out.push_str(value);
+            return;
+        }
+
+        let mut lexer = Token::lexer(value);
+        while let Some(token) = lexer.next() {
+            let (name, format) = match token {
+                Token::Variable(x) => reference(x),
+                _ => {
+                    out.push_str(lexer.slice());
+                    continue;
+                }
+            };
+
+            let (found, referenced) = match self.lookup(language, name) {
+                Some(x) => x,
+                None => {
+                    out.push_str(&unresolved(name));
+                    continue;
+                }
+            };
+            if let Some(start) = stack.iter().position(|x| *x == (found, name)) {
+                cycles.push(stack[start..].iter().map(|(language, key)| ((*language).clone(), key.to_string())).collect());
+                out.push_str(&unresolved(name));
+                continue;
+            }
+
+            // `$key|Y$` colours the inserted text, other formats only apply to numbers the game fills in
+            let color = format.filter(|x| x.len() == 1 && "WTLPRSHYGEBM".contains(*x));
+            if let Some(color) = color {
+                out.push('§');
+                out.push_str(color);
+            }
+            stack.push((found, name));
+            self.expand(language, referenced, stack, cycles, out);
+            stack.pop();
+            if color.is_some() {
+                out.push_str("§!");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::descriptor::ModDescriptor;
+    use crate::Mod;
+
+    fn provider() -> Mod {
+        Mod::empty("mod".into(), "mod".to_string(), ModDescriptor::named("Mod"))
+    }
+
+    /// Merged keys of `(language, key, value)`
+    fn maps<'a>(provider: &'a Mod, keys: &[(Languages, &'a str, &'a str)]) -> HashMap<Languages, BTreeMap<&'a str, Provided<'a>>> {
+        let mut maps: HashMap<Languages, BTreeMap<&str, Provided>> = HashMap::new();
+        for (language, key, value) in keys {
+            maps.entry(language.clone()).or_default().insert(key, Provided { value, provider });
+        }
+        maps
+    }
+
+    fn resolve(maps: &HashMap<Languages, BTreeMap<&str, Provided>>, fallback: &FallbackChain, language: Languages, key: &str) -> (String, Vec<Cycle>) {
+        let mut cycles = vec![];
+        let value = maps[&language][key].value;
+        let resolved = Resolver::new(maps, fallback).resolve(&language, key, value, &mut cycles);
+        (resolved, cycles)
+    }
+
+    #[test]
+    fn expands_nested_references() {
+        let provider = provider();
+        let maps = maps(&provider, &[
+            (Languages::English, "a", "$b$ and $c|Y$"),
+            (Languages::English, "b", "B has $c$"),
+            (Languages::English, "c", "C"),
+        ]);
+
+        assert_eq!(resolve(&maps, &FallbackChain::default(), Languages::English, "a"), ("B has C and §YC§!".to_string(), vec![]));
+    }
+
+    #[test]
+    fn looks_up_missing_keys_along_the_fallback_chain() {
+        let provider = provider();
+        let maps = maps(&provider, &[
+            (Languages::English, "a", "$both$, $chinese$, $default$"),
+            (Languages::English, "both", "English"),
+            (Languages::SimplifiedChinese, "both", "中文"),
+            (Languages::SimplifiedChinese, "chinese", "只有中文"),
+            (Languages::Default, "default", "Default"),
+        ]);
+        let fallback: FallbackChain = "simp_chinese".parse().unwrap();
+
+        assert_eq!(resolve(&maps, &fallback, Languages::English, "a").0, "English, 只有中文, Default");
+    }
+
+    #[test]
+    fn leaves_cycles_unresolved() {
+        let provider = provider();
+        let maps = maps(&provider, &[
+            (Languages::English, "a", "A $b$"),
+            (Languages::English, "b", "B $a$"),
+            (Languages::English, "c", "C $c$"),
+        ]);
+
+        let (resolved, cycles) = resolve(&maps, &FallbackChain::default(), Languages::English, "a");
+        assert_eq!(resolved, "A B {?a}");
+        assert_eq!(cycles, vec![vec![(Languages::English, "a".to_string()), (Languages::English, "b".to_string())]]);
+
+        let (resolved, cycles) = resolve(&maps, &FallbackChain::default(), Languages::English, "c");
+        assert_eq!(resolved, "C {?c}");
+        assert_eq!(cycles, vec![vec![(Languages::English, "c".to_string())]]);
+    }
+
+    #[test]
+    fn marks_unknown_references() {
+        let provider = provider();
+        let maps = maps(&provider, &[(Languages::English, "a", "$nope$ and $nope|Y$, 50$")]);
+
+        assert_eq!(unresolved("nope"), "{?nope}");
+        assert_eq!(resolve(&maps, &FallbackChain::default(), Languages::English, "a"), ("{?nope} and {?nope}, 50$".to_string(), vec![]));
+    }
+
+    #[test]
+    fn leaves_dollar_signs_in_text_alone() {
+        let provider = provider();
+        let maps = maps(&provider, &[
+            (Languages::English, "a", "Costs 50$ and 20$ per $b$"),
+            (Languages::English, "b", "B"),
+        ]);
+
+        assert_eq!(resolve(&maps, &FallbackChain::default(), Languages::English, "a"), ("Costs 50$ and 20$ per B".to_string(), vec![]));
+    }
+}
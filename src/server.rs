@@ -0,0 +1,434 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use axum::extract::{Path, Query, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::{Json, Router};
+use log::info;
+use serde::{Deserialize, Serialize};
+use tower_http::cors::CorsLayer;
+use crate::coverage::Coverage;
+use crate::data::{ResearchArea, Technology};
+use crate::localisation::{FallbackChain, Languages, Text};
+use crate::markup::Markup;
+use crate::{GameData, Mod};
+
+pub const DEFAULT_ADDR: &str = "127.0.0.1:8080";
+
+#[derive(Serialize, Clone)]
+pub struct ModSummary {
+    pub name: String,
+    pub id: String,
+    pub remote_file_id: Option<String>,
+    pub version: Option<String>,
+    pub path: String,
+    pub technologies: usize,
+
+    /// Folders of the game and earlier mods this mod replaced
+    pub replace_path: Vec<String>,
+}
+
+impl ModSummary {
+    pub fn new(x: &Mod) -> ModSummary {
+        ModSummary {
+            name: x.descriptor.name.to_string(),
+            id: x.id.clone(),
+            remote_file_id: x.descriptor.remote_file_id.clone(),
+            version: x.descriptor.version.clone(),
+            path: x.path.to_string_lossy().to_string(),
+            technologies: x.technologies.len(),
+            replace_path: x.descriptor.replace_path.clone(),
+        }
+    }
+}
+
+/// Immutable view of the loaded game data shared by all handlers
+#[derive(Default)]
+pub struct ApiState {
+    mods: Vec<ModSummary>,
+    technologies: BTreeMap<String, Technology>,
+
+    /// Reverse prerequisite edges
+    dependents: HashMap<String, Vec<String>>,
+
+    coverage: Vec<Coverage>,
+
+    /// Where `label` of a technology comes from when the requested language lacks it
+    fallback: FallbackChain,
+}
+
+impl ApiState {
+    pub fn new(data: GameData) -> ApiState {
+        let mods = data.mods
+            .iter()
+            .map(ModSummary::new)
+            .collect();
+
+        let technologies: BTreeMap<String, Technology> = data.technologies
+            .into_iter()
+            .map(|x| (x.id.to_string(), x))
+            .collect();
+
+        let mut dependents: HashMap<String, Vec<String>> = HashMap::new();
+        technologies.values().for_each(|tech| {
+            tech.prerequisites.iter().filter(|x| *x != &tech.id).for_each(|prev| {
+                dependents.entry(prev.to_string()).or_default().push(tech.id.to_string());
+            });
+        });
+
+        ApiState {
+            mods,
+            technologies,
+            dependents,
+            coverage: data.coverage,
+            fallback: data.fallback,
+        }
+    }
+
+    /// Breadth-first walk over prerequisites (`up`) or dependents, excluding `id` itself
+    fn walk(&self, id: &str, up: bool) -> Vec<String> {
+        let mut visited = BTreeSet::new();
+        let mut queue = VecDeque::from([id.to_string()]);
+        let mut ret = vec![];
+
+        while let Some(current) = queue.pop_front() {
+            let next: Vec<String> = if up {
+                self.technologies.get(&current).map(|x| x.prerequisites.clone()).unwrap_or_default()
+            } else {
+                self.dependents.get(&current).cloned().unwrap_or_default()
+            };
+
+            for x in next {
+                if x != id && visited.insert(x.to_string()) {
+                    ret.push(x.to_string());
+                    queue.push_back(x);
+                }
+            }
+        }
+
+        ret
+    }
+
+    /// Every technology needed to research `id`, ordered so prerequisites come first and ending with `id`
+    fn research_path(&self, id: &str) -> Vec<String> {
+        fn visit(state: &ApiState, id: &str, visited: &mut BTreeSet<String>, ret: &mut Vec<String>) {
+            if !visited.insert(id.to_string()) {
+                return;
+            }
+            if let Some(tech) = state.technologies.get(id) {
+                tech.prerequisites.iter().for_each(|x| visit(state, x, visited, ret));
+            }
+            ret.push(id.to_string());
+        }
+
+        let mut ret = vec![];
+        visit(self, id, &mut BTreeSet::new(), &mut ret);
+        ret
+    }
+}
+
+/// Handle to the served data, swapped as a whole when watch mode reloads files
+#[derive(Clone, Default)]
+pub struct SharedState(Arc<RwLock<Arc<ApiState>>>);
+
+impl SharedState {
+    pub fn new(state: ApiState) -> SharedState {
+        SharedState(Arc::new(RwLock::new(Arc::new(state))))
+    }
+
+    pub fn get(&self) -> Arc<ApiState> {
+        self.0.read().unwrap().clone()
+    }
+
+    pub fn replace(&self, state: ApiState) {
+        *self.0.write().unwrap() = Arc::new(state);
+    }
+}
+
+#[derive(Serialize)]
+struct TechnologyResponse<'a> {
+    id: &'a str,
+    modid: &'a str,
+    cost: u64,
+    tier: Option<&'a str>,
+    category: Option<&'a str>,
+    area: &'a ResearchArea,
+    prerequisites: &'a [String],
+    start_tech: bool,
+
+    /// Name in the requested language or the first language of the fallback chain having one
+    label: Option<String>,
+
+    /// Every language unless one was requested
+    localisation: HashMap<&'a Languages, &'a Text>,
+}
+
+impl<'a> TechnologyResponse<'a> {
+    fn new(tech: &'a Technology, lang: Option<&Languages>, fallback: &FallbackChain) -> TechnologyResponse<'a> {
+        let name = |lang: &Languages| tech.localisation.get(lang).map(|x| x.name.as_ref().unwrap_or(&x.value)).filter(|x| !x.is_empty());
+        let label = match lang {
+            Some(lang) => fallback.starting_with(lang).find_map(name),
+            None => fallback.find(name),
+        };
+
+        TechnologyResponse {
+            id: &tech.id,
+            modid: &tech.modid,
+            cost: tech.cost,
+            tier: tech.tier.as_deref(),
+            category: tech.category.as_deref(),
+            area: &tech.area,
+            prerequisites: &tech.prerequisites,
+            start_tech: tech.start_tech,
+            label: label.map(|x| Markup::parse(x).to_plain()),
+            localisation: tech.localisation
+                .iter()
+                .filter(|(k, _)| lang.is_none_or(|lang| *k == lang))
+                .collect(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct LanguageQuery {
+    lang: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct SearchQuery {
+    lang: Option<String>,
+
+    /// Case-insensitive match against the id and the localised name
+    q: Option<String>,
+    area: Option<String>,
+    limit: Option<usize>,
+}
+
+pub enum ApiError {
+    NotFound(String),
+    BadRequest(String),
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let (status, message) = match self {
+            ApiError::NotFound(message) => (StatusCode::NOT_FOUND, message),
+            ApiError::BadRequest(message) => (StatusCode::BAD_REQUEST, message),
+        };
+        (status, Json(serde_json::json!({ "error": message }))).into_response()
+    }
+}
+
+fn parse_language(lang: &Option<String>) -> Result<Option<Languages>, ApiError> {
+    lang.as_deref()
+        .map(|x| Languages::from_str(x.trim_start_matches("l_")).map_err(|e| ApiError::BadRequest(e.to_string())))
+        .transpose()
+}
+
+fn get_technology<'a>(state: &'a ApiState, id: &str) -> Result<&'a Technology, ApiError> {
+    state.technologies.get(id).ok_or_else(|| ApiError::NotFound(format!("Technology {} not found", id)))
+}
+
+async fn list_mods(State(state): State<SharedState>) -> Json<Vec<ModSummary>> {
+    let state = state.get();
+    Json(state.mods.clone())
+}
+
+async fn coverage(State(state): State<SharedState>) -> Json<Vec<Coverage>> {
+    let state = state.get();
+    Json(state.coverage.clone())
+}
+
+async fn search_technologies(State(state): State<SharedState>, Query(query): Query<SearchQuery>) -> Result<Response, ApiError> {
+    let state = state.get();
+    let lang = parse_language(&query.lang)?;
+    let needle = query.q.as_ref().map(|x| x.to_lowercase());
+
+    let techs: Vec<TechnologyResponse> = state.technologies
+        .values()
+        .filter(|x| query.area.as_ref().is_none_or(|area| x.area.as_ref() == area))
+        .filter(|x| needle.as_ref().is_none_or(|needle| {
+            x.id.to_lowercase().contains(needle) || x.localisation
+                .iter()
+                .filter(|(k, _)| lang.as_ref().is_none_or(|lang| *k == lang))
+                .any(|(_, v)| v.name.as_ref().unwrap_or(&v.value).to_lowercase().contains(needle))
+        }))
+        .take(query.limit.unwrap_or(usize::MAX))
+        .map(|x| TechnologyResponse::new(x, lang.as_ref(), &state.fallback))
+        .collect();
+
+    Ok(Json(techs).into_response())
+}
+
+async fn technology(State(state): State<SharedState>, Path(id): Path<String>, Query(query): Query<LanguageQuery>) -> Result<Response, ApiError> {
+    let state = state.get();
+    let lang = parse_language(&query.lang)?;
+    Ok(Json(TechnologyResponse::new(get_technology(&state, &id)?, lang.as_ref(), &state.fallback)).into_response())
+}
+
+async fn ancestors(State(state): State<SharedState>, Path(id): Path<String>) -> Result<Json<Vec<String>>, ApiError> {
+    let state = state.get();
+    get_technology(&state, &id)?;
+    Ok(Json(state.walk(&id, true)))
+}
+
+async fn descendants(State(state): State<SharedState>, Path(id): Path<String>) -> Result<Json<Vec<String>>, ApiError> {
+    let state = state.get();
+    get_technology(&state, &id)?;
+    Ok(Json(state.walk(&id, false)))
+}
+
+async fn research_path(State(state): State<SharedState>, Path(id): Path<String>) -> Result<Json<Vec<String>>, ApiError> {
+    let state = state.get();
+    get_technology(&state, &id)?;
+    Ok(Json(state.research_path(&id)))
+}
+
+/// Routes of the API, usable in-process with `tower::ServiceExt::oneshot`
+pub fn router(state: SharedState) -> Router {
+    Router::new()
+        .route("/api/mods", get(list_mods))
+        .route("/api/coverage", get(coverage))
+        .route("/api/techs", get(search_technologies))
+        .route("/api/techs/:id", get(technology))
+        .route("/api/techs/:id/ancestors", get(ancestors))
+        .route("/api/techs/:id/descendants", get(descendants))
+        .route("/api/techs/:id/path", get(research_path))
+        .layer(CorsLayer::permissive())
+        .with_state(state)
+}
+
+pub async fn serve(state: SharedState, addr: SocketAddr) -> anyhow::Result<()> {
+    let app = router(state);
+
+    info!("Serving tech tree API on http://{}", addr);
+    axum::Server::bind(&addr).serve(app.into_make_service()).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use axum::http::Request;
+    use serde_json::Value;
+    use tower::ServiceExt;
+
+    fn technology(id: &str, area: ResearchArea, prerequisites: &[&str], names: &[(Languages, &str)]) -> Technology {
+        Technology {
+            id: id.to_string(),
+            modid: "Stellaris".to_string(),
+            area,
+            prerequisites: prerequisites.iter().map(|x| x.to_string()).collect(),
+            localisation: names
+                .iter()
+                .map(|(language, name)| (language.clone(), Text { value: name.to_string(), name: None, description: None }))
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    /// lasers_1 <- lasers_2 <- lasers_3 -> shields_1, lasers_3 also needs shields_1
+    fn state() -> SharedState {
+        let data = GameData {
+            mods: vec![],
+            localisations: HashMap::new(),
+            localisation_providers: HashMap::new(),
+            technologies: vec![
+                technology("tech_lasers_1", ResearchArea::Physics, &[], &[(Languages::English, "Red Lasers"), (Languages::SimplifiedChinese, "红色激光")]),
+                technology("tech_lasers_2", ResearchArea::Physics, &["tech_lasers_1"], &[(Languages::English, "Blue Lasers")]),
+                technology("tech_shields_1", ResearchArea::Physics, &[], &[(Languages::English, "Deflectors")]),
+                technology("tech_lasers_3", ResearchArea::Physics, &["tech_lasers_2", "tech_shields_1"], &[]),
+                technology("tech_robots", ResearchArea::Engineering, &[], &[(Languages::English, "Robotic Workers")]),
+            ],
+            coverage: vec![],
+            fallback: FallbackChain::default(),
+        };
+        SharedState::new(ApiState::new(data))
+    }
+
+    async fn get(uri: &str) -> (StatusCode, Value) {
+        let response = router(state()).oneshot(Request::get(uri).body(Body::empty()).unwrap()).await.unwrap();
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+    }
+
+    fn ids(value: &Value) -> Vec<&str> {
+        value.as_array().unwrap().iter().map(|x| x.as_str().or_else(|| x["id"].as_str()).unwrap()).collect()
+    }
+
+    #[tokio::test]
+    async fn lists_and_searches_technologies() {
+        let (status, body) = get("/api/techs").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(ids(&body), ["tech_lasers_1", "tech_lasers_2", "tech_lasers_3", "tech_robots", "tech_shields_1"]);
+
+        let (_, body) = get("/api/techs?q=LASERS&lang=english").await;
+        assert_eq!(ids(&body), ["tech_lasers_1", "tech_lasers_2", "tech_lasers_3"]);
+
+        let (_, body) = get("/api/techs?q=robotic").await;
+        assert_eq!(ids(&body), ["tech_robots"]);
+
+        let (_, body) = get("/api/techs?area=physics&limit=2").await;
+        assert_eq!(ids(&body), ["tech_lasers_1", "tech_lasers_2"]);
+    }
+
+    #[tokio::test]
+    async fn returns_one_technology_in_the_requested_language() {
+        let (status, body) = get("/api/techs/tech_lasers_1?lang=english").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["label"], "Red Lasers");
+        assert_eq!(body["localisation"].as_object().unwrap().keys().collect::<Vec<_>>(), ["english"]);
+
+        // The fallback chain starts at Chinese
+        let (_, body) = get("/api/techs/tech_lasers_1").await;
+        assert_eq!(body["label"], "红色激光");
+        assert_eq!(body["localisation"].as_object().unwrap().len(), 2);
+
+        let (_, body) = get("/api/techs/tech_lasers_2?lang=l_simp_chinese").await;
+        assert_eq!(body["label"], "Blue Lasers");
+    }
+
+    #[tokio::test]
+    async fn walks_ancestors_and_descendants() {
+        let (status, body) = get("/api/techs/tech_lasers_3/ancestors").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(ids(&body), ["tech_lasers_2", "tech_shields_1", "tech_lasers_1"]);
+
+        let (_, body) = get("/api/techs/tech_lasers_1/descendants").await;
+        assert_eq!(ids(&body), ["tech_lasers_2", "tech_lasers_3"]);
+
+        let (_, body) = get("/api/techs/tech_robots/descendants").await;
+        assert!(ids(&body).is_empty());
+    }
+
+    #[tokio::test]
+    async fn orders_the_research_path_prerequisites_first() {
+        let (status, body) = get("/api/techs/tech_lasers_3/path").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(ids(&body), ["tech_lasers_1", "tech_lasers_2", "tech_shields_1", "tech_lasers_3"]);
+    }
+
+    #[tokio::test]
+    async fn rejects_unknown_technologies_and_bad_queries() {
+        for uri in ["/api/techs/tech_nope", "/api/techs/tech_nope/ancestors", "/api/techs/tech_nope/descendants", "/api/techs/tech_nope/path"] {
+            let (status, body) = get(uri).await;
+            assert_eq!(status, StatusCode::NOT_FOUND, "{}", uri);
+            assert_eq!(body["error"], "Technology tech_nope not found");
+        }
+
+        let (status, body) = get("/api/techs/tech_lasers_1?lang=not-a-language").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(body["error"].is_string());
+
+        let (status, _) = get("/api/techs?lang=bad%20language").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let (status, _) = get("/api/techs?limit=many").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+}
@@ -53,22 +53,6 @@ impl TechnologyTree {
             }
         }
     }
-
-    pub fn find(&self, root: Rc<Node>, tech: Rc<Technology>) -> Option<Rc<Node>> {
-        if (root.data).as_ref().map_or(false, |x| x == &tech) {
-            return Some(root);
-        }
-
-        return root.next.borrow().iter().find(|x| self.find((*x).clone(), tech.clone()).is_some()).cloned();
-    }
-
-    pub fn find_by_id(&self, root: Rc<Node>, id: &str) -> Option<Rc<Node>> {
-        if root.name.as_str() == id {
-            return Some(root);
-        }
-
-        return root.next.borrow().iter().find(|x| self.find_by_id((*x).clone(), id).is_some()).cloned();
-    }
 }
 
 pub struct Node {
@@ -0,0 +1,241 @@
+use std::collections::BTreeSet;
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::time::SystemTime;
+#[cfg(test)]
+use std::collections::BTreeMap;
+#[cfg(test)]
+use xxhash_rust::xxh3::xxh3_64;
+
+/// What a [Vfs] knows about a file or folder without reading it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Metadata {
+    pub is_dir: bool,
+    pub len: u64,
+
+    /// Last modification if the backend tracks one
+    pub modified: Option<SystemTime>,
+
+    /// Checksum of the content known without reading it, like the CRC-32 in a zip directory
+    pub checksum: Option<u64>,
+}
+
+/// A file or folder directly inside a folder
+#[derive(Debug, Clone, PartialEq)]
+pub struct DirEntry {
+    pub path: PathBuf,
+    pub is_dir: bool,
+}
+
+/// Where the readers find game and mod files: the disk, a mounted archive or memory.
+/// Paths are the full paths the user gives, each backend answers for the ones it holds.
+pub trait Vfs: Send + Sync {
+    /// Files and folders directly inside the folder `dir`, in no particular order
+    fn read_dir(&self, dir: &Path) -> io::Result<Vec<DirEntry>>;
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>>;
+
+    fn metadata(&self, path: &Path) -> io::Result<Metadata>;
+
+    /// The plain file on disk holding `path`, large files are mapped instead of read
+    fn disk_path(&self, _path: &Path) -> Option<PathBuf> {
+        None
+    }
+
+    /// Makes the content of the zip at `path` readable as a folder at `path`, see [crate::archive::Mounted]
+    fn mount(&self, _path: &Path) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "archives can only be read through a mounted file system"))
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.metadata(path).is_ok()
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        self.metadata(path).is_ok_and(|x| x.is_dir)
+    }
+
+    fn is_file(&self, path: &Path) -> bool {
+        self.metadata(path).is_ok_and(|x| !x.is_dir)
+    }
+
+    /// `path` with every part that does not exist replaced by an entry of its parent folder
+    /// spelled the same ignoring case, the way Windows finds it
+    fn resolve(&self, path: &Path) -> PathBuf {
+        if self.exists(path) {
+            return path.to_path_buf();
+        }
+
+        let mut resolved = PathBuf::new();
+        for component in path.components() {
+            let next = resolved.join(component);
+            if !matches!(component, Component::Normal(_)) || self.exists(&next) {
+                resolved = next;
+                continue;
+            }
+
+            let parent = if resolved.as_os_str().is_empty() { Path::new(".") } else { resolved.as_path() };
+            let name = component.as_os_str().to_string_lossy();
+            let found = self.read_dir(parent).ok().and_then(|entries| {
+                entries
+                    .into_iter()
+                    .filter_map(|x| x.path.file_name().map(|x| x.to_os_string()))
+                    .find(|x| x.to_string_lossy().eq_ignore_ascii_case(&name))
+            });
+            resolved = match found {
+                Some(found) => resolved.join(found),
+                None => next,
+            };
+        }
+        resolved
+    }
+
+    /// Files in `dir` with extension `ext`, with `recursive` also those in subfolders, sorted by path.
+    /// Folders and extensions are matched ignoring case like on Windows, the paths returned are
+    /// spelled as the backend holds them, see [case_folded].
+    fn list_files(&self, dir: &Path, ext: &str, recursive: bool) -> io::Result<Vec<PathBuf>> {
+        let mut files = vec![];
+        let mut pending = vec![];
+        let mut entries = self.read_dir(&self.resolve(dir))?;
+        loop {
+            for x in entries {
+                if x.is_dir {
+                    if recursive {
+                        pending.push(x.path);
+                    }
+                } else if x.path.extension().and_then(|s| s.to_str()).is_some_and(|s| s.eq_ignore_ascii_case(ext)) {
+                    files.push(x.path);
+                }
+            }
+            entries = match pending.pop() {
+                // A subfolder that cannot be read is skipped like a missing one
+                Some(dir) => self.read_dir(&dir).unwrap_or_default(),
+                None => break,
+            };
+        }
+        files.sort();
+        Ok(files)
+    }
+}
+
+/// Places in `files`, listed by [Vfs::list_files] for `dir` and `ext`, that are only found because
+/// case is ignored: the folder as spelled by the backend, or a file whose extension is spelled differently
+pub fn case_folded(dir: &Path, ext: &str, files: &[PathBuf]) -> BTreeSet<PathBuf> {
+    let depth = dir.components().count();
+    files
+        .iter()
+        .flat_map(|x| {
+            // `dir` as spelled by the backend, it has as many parts as `dir`
+            let folder = Some(x.components().take(depth).collect()).filter(|_| !x.starts_with(dir));
+            let file = Some(x.clone()).filter(|x| x.extension().is_some_and(|x| x != ext));
+            folder.into_iter().chain(file)
+        })
+        .collect()
+}
+
+/// The disk through `std::fs`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Os;
+
+impl Vfs for Os {
+    fn read_dir(&self, dir: &Path) -> io::Result<Vec<DirEntry>> {
+        Ok(fs::read_dir(dir)?
+            .filter_map(|x| x.ok())
+            .map(|x| {
+                // Links are followed, workshop folders are often linked into place
+                let is_dir = x.file_type().is_ok_and(|t| t.is_dir() || (t.is_symlink() && x.path().is_dir()));
+                DirEntry { path: x.path(), is_dir }
+            })
+            .collect())
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        fs::read(path)
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        let metadata = fs::metadata(path)?;
+        Ok(Metadata {
+            is_dir: metadata.is_dir(),
+            len: metadata.len(),
+            modified: metadata.modified().ok(),
+            checksum: None,
+        })
+    }
+
+    fn disk_path(&self, path: &Path) -> Option<PathBuf> {
+        Some(path.to_path_buf())
+    }
+}
+
+/// Files kept in memory, folders exist as long as a file is in them. Builds a game and mods
+/// without touching the disk.
+#[cfg(test)]
+#[derive(Debug, Clone, Default)]
+pub struct Memory {
+    files: BTreeMap<PathBuf, Vec<u8>>,
+}
+
+#[cfg(test)]
+impl Memory {
+    /// Adds the file at `path`, replacing one already there
+    pub fn insert<P: Into<PathBuf>, D: Into<Vec<u8>>>(&mut self, path: P, data: D) {
+        self.files.insert(path.into(), data.into());
+    }
+
+    /// Deletes the file at `path`
+    pub fn remove<P: AsRef<Path>>(&mut self, path: P) {
+        self.files.remove(path.as_ref());
+    }
+
+    pub fn with_file<P: Into<PathBuf>, D: Into<Vec<u8>>>(mut self, path: P, data: D) -> Memory {
+        self.insert(path, data);
+        self
+    }
+
+    /// Files in the folder `dir` or its subfolders, relative to it
+    fn below<'a>(&'a self, dir: &'a Path) -> impl Iterator<Item = (&'a Path, &'a Vec<u8>)> + 'a {
+        // Relative paths are kept relative to the current folder
+        let dir = if dir == Path::new(".") { Path::new("") } else { dir };
+        self.files
+            .range(dir.to_path_buf()..)
+            .map_while(move |(path, data)| path.strip_prefix(dir).ok().map(|x| (x, data)))
+            .filter(|(x, _)| x.components().next().is_some())
+    }
+}
+
+#[cfg(test)]
+impl Vfs for Memory {
+    fn read_dir(&self, dir: &Path) -> io::Result<Vec<DirEntry>> {
+        let mut entries = BTreeMap::new();
+        for (relative, _) in self.below(dir) {
+            let mut components = relative.components();
+            if let Some(name) = components.next() {
+                let is_dir = components.next().is_some();
+                *entries.entry(dir.join(name)).or_insert(false) |= is_dir;
+            }
+        }
+        if entries.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "no such folder in memory"));
+        }
+        Ok(entries.into_iter().map(|(path, is_dir)| DirEntry { path, is_dir }).collect())
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        self.files.get(path).cloned().ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no such file in memory"))
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        match self.files.get(path) {
+            Some(data) => Ok(Metadata {
+                is_dir: false,
+                len: data.len() as u64,
+                modified: None,
+                checksum: Some(xxh3_64(data)),
+            }),
+            None if self.below(path).next().is_some() => Ok(Metadata { is_dir: true, len: 0, modified: None, checksum: None }),
+            None => Err(io::Error::new(io::ErrorKind::NotFound, "no such file in memory")),
+        }
+    }
+}
@@ -0,0 +1,307 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use anyhow::anyhow;
+use log::{error, info, warn};
+use measure_time::trace_time;
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use crate::checks::FileIndex;
+use crate::layout::LayoutOptions;
+use crate::localisation::{parse_localisation_async, FallbackChain, Localisation, LocalisationFile};
+use crate::server::{ApiState, SharedState};
+use crate::descriptor::{read_game_descriptor, LocatedMod, ReplacedPaths, GAME_ID};
+use crate::diagnostics::{Diagnostics, Reporter};
+use crate::load_order;
+use crate::vfs::Vfs;
+use crate::{build_game_data, cache, locate_mods, read_technology_file, read_variable_file, Mod, TechnologyFile, VariableFile};
+
+/// Quiet period before a batch of file events is processed, editors tend to write a file several times
+const DEBOUNCE: Duration = Duration::from_millis(300);
+
+/// Where rebuilt data goes after every batch of changes
+pub enum WatchTarget {
+    /// Rewrite the exported JSON and HTML files
+    Export(LayoutOptions),
+
+    /// Swap the data served by the local API server
+    Serve(SharedState),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FileKind {
+    Variables,
+    Technology,
+    Localisation,
+}
+
+impl FileKind {
+    /// Classifies a path relative to a mod root, files the loader does not read are ignored
+    fn of(relative: &Path) -> Option<FileKind> {
+        // Folders and extensions are matched ignoring case like the readers do
+        let relative = PathBuf::from(relative.to_string_lossy().to_lowercase());
+        let ext = relative.extension().and_then(|x| x.to_str())?;
+        if ext == "txt" && relative.parent() == Some(Path::new("common/scripted_variables")) {
+            Some(FileKind::Variables)
+        } else if ext == "txt" && relative.parent() == Some(Path::new("common/technology")) {
+            Some(FileKind::Technology)
+        } else if ext == "yml" && relative.starts_with("localisation") {
+            Some(FileKind::Localisation)
+        } else {
+            None
+        }
+    }
+}
+
+/// Per-file parse results of one mod, merged into a [`Mod`] after every change
+struct ModSources {
+    base: Mod,
+    variables: BTreeMap<PathBuf, VariableFile>,
+    technologies: BTreeMap<PathBuf, TechnologyFile>,
+    localisations: BTreeMap<PathBuf, (Localisation, FileIndex)>,
+}
+
+impl ModSources {
+    async fn scan<V: Vfs>(vfs: &V, base: Mod, reporter: Reporter<'_>) -> ModSources {
+        let mut sources = ModSources {
+            base,
+            variables: BTreeMap::new(),
+            technologies: BTreeMap::new(),
+            localisations: BTreeMap::new(),
+        };
+
+        let root = sources.base.path.clone();
+        let files = [
+            vfs.list_files(&root.join("common").join("scripted_variables"), "txt", false).unwrap_or_default(),
+            vfs.list_files(&root.join("common").join("technology"), "txt", false).unwrap_or_default(),
+            vfs.list_files(&root.join("localisation"), "yml", true).unwrap_or_default(),
+        ];
+
+        for file in files.into_iter().flatten() {
+            sources.reload(vfs, &file, reporter).await;
+        }
+        sources
+    }
+
+    /// Re-parses a single file, or forgets it if it is gone, returns whether the mod was affected.
+    /// A file that fails to parse is reported and its last good parse kept.
+    async fn reload<V: Vfs>(&mut self, vfs: &V, file: &Path, reporter: Reporter<'_>) -> bool {
+        let kind = match file.strip_prefix(&self.base.path).ok().and_then(FileKind::of) {
+            Some(kind) => kind,
+            None => return false,
+        };
+
+        if !vfs.is_file(file) {
+            return self.variables.remove(file).is_some()
+                || self.technologies.remove(file).is_some()
+                || self.localisations.remove(file).is_some();
+        }
+
+        match kind {
+            FileKind::Variables => match read_variable_file(vfs, file) {
+                Ok(x) => { self.variables.insert(file.to_path_buf(), x); }
+                Err(e) => reporter.report(e),
+            },
+            FileKind::Technology => match read_technology_file(vfs, file) {
+                Ok(x) => { self.technologies.insert(file.to_path_buf(), x); }
+                Err(e) => reporter.report(e),
+            },
+            FileKind::Localisation => match parse_localisation_async(vfs, file).await {
+                Ok(x) => { self.localisations.insert(file.to_path_buf(), x); }
+                Err(e) => reporter.report(e),
+            },
+        }
+        true
+    }
+
+    /// Merges the parsed files, leaving out those in folders a later mod replaces
+    fn compose(&self, replaced: &ReplacedPaths) -> Mod {
+        let root = &self.base.path;
+        let mut variables = BTreeMap::new();
+        let mut script_files = vec![];
+        self.variables.iter().filter(|(file, _)| !replaced.covers(root, file)).for_each(|(_, (vars, index))| {
+            variables.extend(vars.iter().map(|(k, v)| (k.clone(), v.clone())));
+            script_files.push(index.clone());
+        });
+        let mut technologies = HashMap::new();
+        self.technologies.iter().filter(|(file, _)| !replaced.covers(root, file)).for_each(|(_, (vars, techs, index))| {
+            variables.extend(vars.iter().map(|(k, v)| (k.clone(), v.clone())));
+            technologies.extend(techs.iter().map(|(k, v)| (k.clone(), v.clone())));
+            script_files.push(index.clone());
+        });
+
+        Mod {
+            path: self.base.path.clone(),
+            id: self.base.id.clone(),
+            descriptor: self.base.descriptor.clone(),
+            variables,
+            technologies,
+            localisations: self
+                .localisations
+                .iter()
+                .filter(|(file, _)| !replaced.covers(root, file))
+                .map(|(file, x)| LocalisationFile::new(root, file, x.clone()))
+                .collect(),
+            script_files,
+            replaced: replaced.clone(),
+        }
+    }
+}
+
+async fn publish(sources: &[ModSources], fallback: &FallbackChain, target: &WatchTarget) {
+    let data = {
+        trace_time!("Rebuild game data");
+        build_game_data(
+            sources
+                .iter()
+                .enumerate()
+                .map(|(i, x)| x.compose(&ReplacedPaths::of(sources[i + 1..].iter().map(|x| &x.base.descriptor))))
+                .collect(),
+            fallback,
+        )
+    };
+
+    match target {
+        WatchTarget::Export(layout_options) => {
+            if let Err(e) = crate::export(&data, layout_options).await {
+                error!("Writing outputs failed, {}", e);
+            }
+        }
+        WatchTarget::Serve(state) => state.replace(ApiState::new(data)),
+    }
+}
+
+/// Loads the game in `game_path` and mods from `vfs`, then keeps `target` up to date as technology, scripted variable
+/// and localisation files change. Only the changed files are parsed again. The load order is
+/// settled once at start, see [load_order::resolve].
+pub async fn watch<V: Vfs>(vfs: &V, game_path: &Path, folders: &[String], sort_load_order: bool, fallback: &FallbackChain, target: WatchTarget) -> anyhow::Result<()> {
+    let mut sources: Vec<ModSources> = {
+        trace_time!("Parse all mods");
+        let game = read_game_descriptor(vfs, game_path)?;
+        let diagnostics = Diagnostics::default();
+        let mut mods = load_order::resolve(vfs, locate_mods(vfs, folders, &diagnostics), game.version.as_deref(), sort_load_order, &diagnostics);
+
+        // The game loads first, mods override it in the given order
+        mods.insert(0, LocatedMod {
+            path: game_path.to_path_buf(),
+            id: GAME_ID.to_string(),
+            descriptor: game,
+            file: game_path.join("launcher-settings.json"),
+        });
+
+        let mut sources = vec![];
+        for LocatedMod { path, id, descriptor, .. } in mods {
+            let name = descriptor.name.clone();
+            sources.push(ModSources::scan(vfs, Mod::empty(path, id, descriptor), diagnostics.reporter(&name)).await);
+        }
+        crate::emit_diagnostics(vfs, &diagnostics);
+        sources
+    };
+    cache::report();
+
+    publish(&sources, fallback, &target).await;
+
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    let mut watcher: RecommendedWatcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        match event {
+            Ok(event) => event.paths.into_iter().for_each(|x| { let _ = tx.send(x); }),
+            Err(e) => warn!("Watch error, {}", e),
+        }
+    })?;
+
+    // Archives are read once, only changes to folders are watched
+    for x in &sources {
+        watcher.watch(&x.base.path, RecursiveMode::Recursive)?;
+    }
+    info!("Watching {} folders for changes", sources.len());
+
+    while let Some(first) = rx.recv().await {
+        let mut changed = HashSet::from([first]);
+        while let Ok(Some(path)) = tokio::time::timeout(DEBOUNCE, rx.recv()).await {
+            changed.insert(path);
+        }
+
+        let diagnostics = Diagnostics::default();
+        let mut affected = 0;
+        for file in &changed {
+            // Nested folders (e.g. a mod inside the game folder) go to the most specific root
+            if let Some(x) = sources
+                .iter_mut()
+                .filter(|x| file.starts_with(&x.base.path))
+                .max_by_key(|x| x.base.path.as_os_str().len())
+            {
+                let name = x.base.descriptor.name.clone();
+                if x.reload(vfs, file, diagnostics.reporter(&name)).await {
+                    info!("Reloaded {}", file.display());
+                    affected += 1;
+                }
+            }
+        }
+
+        if affected > 0 {
+            crate::emit_diagnostics(vfs, &diagnostics);
+            publish(&sources, fallback, &target).await;
+        }
+    }
+
+    Err(anyhow!("File watcher stopped"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::descriptor::ModDescriptor;
+    use crate::vfs::Memory;
+
+    #[test]
+    fn file_kind_matches_the_folders_the_loader_reads() {
+        let kind = |x: &str| FileKind::of(Path::new(x));
+
+        assert_eq!(kind("common/scripted_variables/00_vars.txt"), Some(FileKind::Variables));
+        assert_eq!(kind("Common/Technology/00_TECH.TXT"), Some(FileKind::Technology));
+        assert_eq!(kind("localisation/english/replace/x_l_english.yml"), Some(FileKind::Localisation));
+        assert_eq!(kind("common/technology/tier/00_tech.txt"), None);
+        assert_eq!(kind("common/technology/readme.md"), None);
+        assert_eq!(kind("common/buildings/00_buildings.txt"), None);
+        assert_eq!(kind("descriptor.mod"), None);
+    }
+
+    #[tokio::test]
+    async fn reload_updates_the_composed_mod_file_by_file() {
+        let mut vfs = Memory::default()
+            .with_file("m/common/scripted_variables/vars.txt", "@cost = 10")
+            .with_file("m/common/technology/tech.txt", "tech_a = { cost = @cost category = { particles } }")
+            .with_file("m/localisation/english/m_l_english.yml", "l_english:\n tech_a:0 \"A\"\n");
+        let diagnostics = Diagnostics::default();
+        let reporter = diagnostics.reporter("Mod");
+        let mut sources = ModSources::scan(&vfs, Mod::empty("m".into(), "m".to_string(), ModDescriptor::named("Mod")), reporter).await;
+
+        let x = sources.compose(&ReplacedPaths::default());
+        assert_eq!(x.variables.keys().collect::<Vec<_>>(), vec!["@cost"]);
+        assert_eq!(x.technologies.keys().collect::<Vec<_>>(), vec!["tech_a"]);
+        assert_eq!(x.localisations.len(), 1);
+        assert_eq!(x.script_files.len(), 2);
+
+        // A broken edit is reported and the last good parse kept
+        vfs.insert("m/common/technology/tech.txt", "tech_a = { cost = ");
+        assert!(sources.reload(&vfs, Path::new("m/common/technology/tech.txt"), reporter).await);
+        assert_eq!(diagnostics.error_count(), 1);
+        assert!(sources.compose(&ReplacedPaths::default()).technologies.contains_key("tech_a"));
+
+        vfs.insert("m/common/technology/tech.txt", "tech_b = { cost = 1 category = { particles } }");
+        assert!(sources.reload(&vfs, Path::new("m/common/technology/tech.txt"), reporter).await);
+        vfs.remove("m/localisation/english/m_l_english.yml");
+        assert!(sources.reload(&vfs, Path::new("m/localisation/english/m_l_english.yml"), reporter).await);
+        assert!(!sources.reload(&vfs, Path::new("m/common/buildings/b.txt"), reporter).await);
+
+        let x = sources.compose(&ReplacedPaths::default());
+        assert_eq!(x.technologies.keys().collect::<Vec<_>>(), vec!["tech_b"]);
+        assert!(x.localisations.is_empty());
+
+        // Folders a later mod replaces are left out
+        let replacing = ModDescriptor { replace_path: vec!["common/technology".to_string()], ..ModDescriptor::named("Later") };
+        let x = sources.compose(&ReplacedPaths::of([&replacing]));
+        assert!(x.technologies.is_empty());
+        assert_eq!(x.variables.keys().collect::<Vec<_>>(), vec!["@cost"]);
+        assert_eq!(x.replaced, ReplacedPaths::of([&replacing]));
+    }
+}
@@ -1,43 +1,131 @@
-use std::collections::{BTreeMap, BTreeSet};
-use std::process::Output;
+//! Parser for Stellaris localisation files (`localisation/**/*_l_<language>.yml`).
+//!
+//! The files look like YAML but are not, a file starts with a `l_<language>:` header followed by
+//! `key:0 "value"` lines. The same line parser backs [`parse_slice`], [`parse_reader`] and,
+//! with the `tokio` feature, [`parse_async_reader`].
 
-#[derive(PartialEq, Eq, Debug, Hash, Copy, Clone, Serialize, EnumString, Display, IntoStaticStr, EnumIter)]
+use std::collections::BTreeMap;
+use std::fmt::{Display as FmtDisplay, Formatter};
+use std::io::{self, BufRead};
+use std::ops::Range;
+use std::str::FromStr;
+use serde::{Deserialize, Serialize, Serializer};
+
+/// Language of a localisation file, tags the game does not ship are kept as [`Languages::Other`].
+/// Ordered as the launcher lists them, then community languages by tag, then [`Languages::Default`].
+#[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Hash, Clone, Default)]
 pub enum Languages {
-    #[strum(serialize = "braz_por")]
     Portuguese,
-
-    #[strum(serialize = "english")]
     English,
-
-    #[strum(serialize = "french")]
     French,
-
-    #[strum(serialize = "german")]
     German,
-
-    #[strum(serialize = "polish")]
     Polish,
-
-    #[strum(serialize = "russian")]
     Russian,
-
-    #[strum(serialize = "spanish")]
     Spanish,
-
-    #[strum(serialize = "simp_chinese")]
     SimplifiedChinese,
+    Japanese,
+    Korean,
 
-    #[strum(serialize = "default")]
+    /// Community translations, e.g. `l_ukrainian`
+    Other(String),
+
+    /// Files without a language
+    #[default]
     Default,
 }
 
-impl Default for Languages {
-    fn default() -> Self {
-        Languages::Default
+impl Languages {
+    /// Languages the game ships
+    pub const KNOWN: [Languages; 10] = [
+        Languages::Portuguese,
+        Languages::English,
+        Languages::French,
+        Languages::German,
+        Languages::Polish,
+        Languages::Russian,
+        Languages::Spanish,
+        Languages::SimplifiedChinese,
+        Languages::Japanese,
+        Languages::Korean,
+    ];
+
+    /// Tag of the language without the `l_`, e.g. `simp_chinese`
+    pub fn as_str(&self) -> &str {
+        match self {
+            Languages::Portuguese => "braz_por",
+            Languages::English => "english",
+            Languages::French => "french",
+            Languages::German => "german",
+            Languages::Polish => "polish",
+            Languages::Russian => "russian",
+            Languages::Spanish => "spanish",
+            Languages::SimplifiedChinese => "simp_chinese",
+            Languages::Japanese => "japanese",
+            Languages::Korean => "korean",
+            Languages::Other(x) => x,
+            Languages::Default => "default",
+        }
+    }
+
+    /// Language named by a file called `<anything>_l_<language>.yml` or `l_<language>.yml`,
+    /// the extension is matched ignoring case like the game does on Windows
+    pub fn of_file_name(name: &str) -> Option<Languages> {
+        let (stem, ext) = name.rsplit_once('.')?;
+        if !ext.eq_ignore_ascii_case("yml") {
+            return None;
+        }
+        let tag = match stem.rfind("_l_") {
+            Some(i) => &stem[i + 3..],
+            None => stem.strip_prefix("l_")?,
+        };
+        tag.parse().ok()
+    }
+}
+
+/// A language tag that is empty or has characters other than ASCII letters, digits and `_`
+#[derive(Debug, Clone, PartialEq)]
+pub struct InvalidLanguage(pub String);
+
+impl FmtDisplay for InvalidLanguage {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Invalid language {:?}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidLanguage {}
+
+impl FromStr for Languages {
+    type Err = InvalidLanguage;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(x) = Languages::KNOWN.iter().find(|x| x.as_str() == s) {
+            return Ok(x.clone());
+        }
+        match s {
+            "default" => Ok(Languages::Default),
+            _ if !s.is_empty() && s.bytes().all(|x| x.is_ascii_alphanumeric() || x == b'_') => Ok(Languages::Other(s.to_string())),
+            _ => Err(InvalidLanguage(s.to_string())),
+        }
+    }
+}
+
+impl FmtDisplay for Languages {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Written as the tag, so maps keyed by language serialise with keys like `simp_chinese`
+impl Serialize for Languages {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
     }
 }
 
-#[derive(PartialEq, Hash, Clone, Default, Debug, Serialize, Deserialize)]
+/// Parsed keys of one file and the language of its header, [`Languages::Default`] without a header
+pub type Localisation = (Languages, BTreeMap<String, String>);
+
+#[derive(PartialEq, Hash, Clone, Default, Debug, Serialize, Deserialize, Eq)]
 pub struct Text {
     pub value: String,
 
@@ -48,93 +136,249 @@ pub struct Text {
     pub description: Option<String>,
 }
 
+/// One meaningful line of a localisation file, spans are byte ranges within the line
+#[derive(Debug, PartialEq)]
+pub enum LocalisationLine<'a> {
+    /// `l_english:`, the language of the keys that follow
+    Header(&'a str),
 
-pub fn fold_localisation_map(map: &BTreeMap<&str, String>) -> BTreeMap<String, Text> {
+    /// `key:0 "value"`, the version number is optional
+    Entry {
+        key: &'a str,
+        key_span: Range<usize>,
+        version: Option<u32>,
 
-    let keys: BTreeSet<&str> = {
-        trace_time!("Mapping localisation keys");
-        map
-            .keys()
-            .map(|x| {
-                x.trim_end_matches("name")
-                    .trim_end_matches("desc")
-                    .trim_end_matches(&[':', '_', '.'])
-            })
-            .collect()
-    };
+        /// Value with `\n`, `\"` and `\\` decoded
+        value: String,
 
-    let mut ret = BTreeMap::new();
-
-    for key in keys {
-        let name = map
-            .get(format!("{}_name", key).as_str())
-            .or(map.get(format!("{}.name", key).as_str()))
-            .map(|x| x.trim_matches('"'));
-        let desc = map
-            .get(format!("{}_desc", key).as_str())
-            .or(map.get(format!("{}.desc", key).as_str()))
-            .map(|x| x.trim_matches('"'));
-        let value = map
-            .get(key)
-            .map(|x| x.trim_matches('"'))
-            .or(name.clone())
-            .or(desc.clone());
-        ret.insert(
-            key.to_string(),
-            Text {
-                value: value.map(|x| x.to_string()).unwrap_or_default(),
-
-                name: name.map(|x| x.to_string()),
-                description: desc.map(|x| x.to_string()),
-            },
-        );
-    }
+        /// Raw text between the quotes
+        value_span: Range<usize>,
+    },
+}
 
-    ret
+/// Problem in one line of a localisation file, `column` is a byte offset within the line
+#[derive(Debug, PartialEq)]
+pub struct LineError {
+    pub column: usize,
+    pub message: String,
 }
 
-pub fn parse_localisation<I: Iterator<Item = String>>(mut lines: I) -> anyhow::Result<(Languages, BTreeMap<String, String>)> {
-    let mut map: BTreeMap<String, String> = BTreeMap::new();
+impl LineError {
+    fn new(column: usize, message: &str) -> LineError {
+        LineError {
+            column,
+            message: message.to_string(),
+        }
+    }
+}
 
-    let mut current_language = Languages::Default;
+/// Tokenizes one line, blank and comment lines give `None`.
+///
+/// A `"` only closes the value when nothing but whitespace or a comment follows it, which is how
+/// the game reads values with unescaped quotes inside. A `#` inside the value is kept.
+pub fn tokenize_line(line: &str) -> Result<Option<LocalisationLine<'_>>, LineError> {
+    let key_start = line.len() - line.trim_start().len();
+    let rest = &line[key_start..];
+    if rest.is_empty() || rest.starts_with('#') {
+        return Ok(None);
+    }
 
-    let mut i = 0;
-    while let Some(line) = lines.next() {
-        let mut line_owned = line.expect("There was an error reading a file");
+    let key_end = key_start + rest.find(|x: char| x == ':' || x == '"' || x == '#' || x.is_whitespace()).unwrap_or(rest.len());
+    if !line[key_end..].starts_with(':') {
+        return Err(LineError::new(key_end, "Expected `:` after the key"));
+    }
+    if key_end == key_start {
+        return Err(LineError::new(key_start, "Missing key before `:`"));
+    }
+    let key = &line[key_start..key_end];
 
-        if i == 0 && line_owned.starts_with("\u{feff}") {
-            line_owned.drain(..3); // Remove BOM
-        }
+    let version_start = key_end + 1;
+    let version_end = version_start + line[version_start..].find(|x: char| !x.is_ascii_digit()).unwrap_or(line.len() - version_start);
+    let version = match &line[version_start..version_end] {
+        "" => None,
+        digits => Some(digits.parse::<u32>().map_err(|_| LineError::new(version_start, "Version number is too large"))?),
+    };
 
-        line_owned.trim_in_place();
+    let quote = line.len() - line[version_end..].trim_start().len();
+    let after = &line[quote..];
+    if after.is_empty() || after.starts_with('#') {
+        return match key.strip_prefix("l_") {
+            Some(language) if version.is_none() => Ok(Some(LocalisationLine::Header(language))),
+            _ => Err(LineError::new(quote, "Expected a quoted value")),
+        };
+    }
+    if !after.starts_with('"') {
+        return Err(LineError::new(quote, "Expected a quoted value"));
+    }
 
-        if line_owned.is_empty() {
-            continue;
+    let value_start = quote + 1;
+    let mut value = String::new();
+    let mut chars = line[value_start..].char_indices();
+    let value_end = loop {
+        match chars.next() {
+            None => return Err(LineError::new(quote, "Unterminated quoted value")),
+            Some((_, '\\')) => match chars.next() {
+                Some((_, 'n')) => value.push('\n'),
+                Some((_, '"')) => value.push('"'),
+                Some((_, '\\')) => value.push('\\'),
+                Some((_, x)) => {
+                    value.push('\\');
+                    value.push(x);
+                }
+                None => value.push('\\'),
+            },
+            Some((i, '"')) => {
+                let tail = line[value_start + i + 1..].trim_start();
+                if tail.is_empty() || tail.starts_with('#') {
+                    break value_start + i;
+                }
+                value.push('"');
+            }
+            Some((_, x)) => value.push(x),
         }
+    };
+
+    Ok(Some(LocalisationLine::Entry {
+        key,
+        key_span: key_start..key_end,
+        version,
+        value,
+        value_span: value_start..value_end,
+    }))
+}
+
+/// Lines of `data` with their byte offset, line breaks and a leading BOM removed
+pub fn split_lines(data: &[u8]) -> impl Iterator<Item = (usize, &[u8])> {
+    let bom = if data.starts_with("\u{feff}".as_bytes()) { 3 } else { 0 };
+    let mut offset = bom;
+    data[bom..].split(|x| *x == b'\n').map(move |line| {
+        let start = offset;
+        offset += line.len() + 1;
+        (start, line.strip_suffix(b"\r").unwrap_or(line))
+    })
+}
 
-        let mut line = line_owned.as_str();
+/// A line that does not parse and was left out, `line` is 1-based and `span` holds the byte
+/// offsets of the offending text in the file
+#[derive(Debug, Clone, PartialEq)]
+pub struct LineDiagnostic {
+    pub line: usize,
+    pub span: Range<usize>,
+    pub message: String,
+}
 
-        if let Some(comment_index) = line_owned.find('#') {
-            line = &line_owned[0..comment_index];
+impl FmtDisplay for LineDiagnostic {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+/// Incremental parser fed one line at a time. Lines that do not parse are skipped and recorded,
+/// the rest of the file is still read.
+#[derive(Default)]
+pub struct Parser {
+    language: Option<Languages>,
+    map: BTreeMap<String, String>,
+    diagnostics: Vec<LineDiagnostic>,
+    line: usize,
+    offset: usize,
+}
+
+impl Parser {
+    /// Parses the next line, with or without its line break
+    pub fn feed(&mut self, raw: &[u8]) {
+        let mut offset = self.offset;
+        self.line += 1;
+        self.offset += raw.len();
+
+        let mut line = raw.strip_suffix(b"\n").unwrap_or(raw);
+        line = line.strip_suffix(b"\r").unwrap_or(line);
+        if self.line == 1 {
+            if let Some(rest) = line.strip_prefix("\u{feff}".as_bytes()) {
+                line = rest;
+                offset += 3;
+            }
         }
 
-        if let Some(collon) = line.find(':') {
-            let (key, value) = line.split_at(collon);
-            if current_language == Languages::Default && key.starts_with("l_") {
-                current_language = key.trim_start_matches("l_").parse::<Languages>().unwrap_or_default();
+        let line = match std::str::from_utf8(line) {
+            Ok(line) => line,
+            Err(e) => {
+                let start = offset + e.valid_up_to();
+                self.diagnostics.push(LineDiagnostic {
+                    line: self.line,
+                    span: start..start + e.error_len().unwrap_or(line.len() - e.valid_up_to()),
+                    message: "Invalid UTF-8, localisation must be saved as UTF-8 with BOM".to_string(),
+                });
+                return;
             }
-            if let Some(left) = value.find('"') {
-                if let Some(right) = value.rfind('"') {
-                    map.insert(
-                        key.to_string(),
-                        (&value[left..right]).trim_matches('"').to_string(),
-                    );
+        };
+
+        match tokenize_line(line) {
+            Ok(Some(LocalisationLine::Header(language))) => {
+                if self.language.is_none() {
+                    self.language = Some(language.parse().unwrap_or_default());
                 }
             }
+            Ok(Some(LocalisationLine::Entry { key, value, .. })) => {
+                self.map.insert(key.to_string(), value);
+            }
+            Ok(None) => {}
+            Err(e) => self.diagnostics.push(LineDiagnostic {
+                line: self.line,
+                // To the end of the line, the rest of it is what could not be read
+                span: offset + e.column..offset + line.trim_end().len().max(e.column),
+                message: e.message,
+            }),
         }
+    }
 
-        i += 1;
+    /// The keys read and the lines that were skipped
+    pub fn finish(self) -> (Localisation, Vec<LineDiagnostic>) {
+        ((self.language.unwrap_or_default(), self.map), self.diagnostics)
     }
+}
+
+pub fn parse_slice(data: &[u8]) -> (Localisation, Vec<LineDiagnostic>) {
+    let mut parser = Parser::default();
+    for line in data.split_inclusive(|x| *x == b'\n') {
+        parser.feed(line);
+    }
+    parser.finish()
+}
 
-    Ok((current_language, map))
+pub fn parse_reader<R: BufRead>(mut reader: R) -> io::Result<(Localisation, Vec<LineDiagnostic>)> {
+    let mut parser = Parser::default();
+    let mut buf = vec![];
+    while reader.read_until(b'\n', &mut buf)? > 0 {
+        parser.feed(&buf);
+        buf.clear();
+    }
+    Ok(parser.finish())
+}
+
+#[cfg(feature = "tokio")]
+pub async fn parse_async_reader<R: tokio::io::AsyncBufRead + Unpin>(mut reader: R) -> io::Result<(Localisation, Vec<LineDiagnostic>)> {
+    use tokio::io::AsyncBufReadExt;
+
+    let mut parser = Parser::default();
+    let mut buf = vec![];
+    while reader.read_until(b'\n', &mut buf).await? > 0 {
+        parser.feed(&buf);
+        buf.clear();
+    }
+    Ok(parser.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_names_name_the_language_whatever_the_case_of_the_extension() {
+        assert_eq!(Languages::of_file_name("techs_l_english.yml"), Some(Languages::English));
+        assert_eq!(Languages::of_file_name("techs_l_english.YML"), Some(Languages::English));
+        assert_eq!(Languages::of_file_name("l_simp_chinese.Yml"), Some(Languages::SimplifiedChinese));
+        assert_eq!(Languages::of_file_name("techs_l_english.txt"), None);
+        assert_eq!(Languages::of_file_name("techs_l_english"), None);
+    }
 }
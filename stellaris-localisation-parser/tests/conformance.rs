@@ -0,0 +1,55 @@
+//! Every `tests/corpus/*.yml` file is parsed by each entry point and compared with the
+//! `.json` file next to it, `{ "language", "entries", "diagnostics": [{ "line", "offset", "length", "message" }] }`.
+
+use std::fs;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use serde_json::{json, Value};
+use stellaris_localisation_parser::{parse_reader, parse_slice, LineDiagnostic, Localisation};
+
+fn corpus() -> Vec<PathBuf> {
+    let mut files: Vec<PathBuf> = fs::read_dir(Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("corpus"))
+        .unwrap()
+        .map(|x| x.unwrap().path())
+        .filter(|x| x.extension().is_some_and(|x| x == "yml"))
+        .collect();
+    files.sort();
+    assert!(!files.is_empty());
+    files
+}
+
+fn to_json(((language, entries), diagnostics): (Localisation, Vec<LineDiagnostic>)) -> Value {
+    let diagnostics: Vec<Value> = diagnostics
+        .into_iter()
+        .map(|x| json!({ "line": x.line, "offset": x.span.start, "length": x.span.len(), "message": x.message }))
+        .collect();
+    json!({ "language": language.to_string(), "entries": entries, "diagnostics": diagnostics })
+}
+
+fn expected(file: &Path) -> Value {
+    serde_json::from_str(&fs::read_to_string(file.with_extension("json")).unwrap()).unwrap()
+}
+
+#[test]
+fn slice() {
+    for file in corpus() {
+        assert_eq!(to_json(parse_slice(&fs::read(&file).unwrap())), expected(&file), "{}", file.display());
+    }
+}
+
+#[test]
+fn reader() {
+    for file in corpus() {
+        let reader = BufReader::new(fs::File::open(&file).unwrap());
+        assert_eq!(to_json(parse_reader(reader).unwrap()), expected(&file), "{}", file.display());
+    }
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn async_reader() {
+    for file in corpus() {
+        let reader = tokio::io::BufReader::new(tokio::fs::File::open(&file).await.unwrap());
+        assert_eq!(to_json(stellaris_localisation_parser::parse_async_reader(reader).await.unwrap()), expected(&file), "{}", file.display());
+    }
+}